@@ -0,0 +1,10 @@
+-- One-off setup for the user the app connects as. The tables themselves are created by the
+-- migrations in /migrations, which need to be run as a user that can create tables (see the README)
+CREATE SCHEMA IF NOT EXISTS betting;
+
+CREATE USER betting_user WITH PASSWORD '<INSERT SECURE PASSWORD HERE>';
+GRANT USAGE ON SCHEMA betting TO betting_user;
+GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA betting TO betting_user;
+GRANT USAGE ON ALL SEQUENCES IN SCHEMA betting TO betting_user;
+ALTER DEFAULT PRIVILEGES FOR ROLE markaronin IN SCHEMA betting GRANT SELECT, UPDATE, INSERT, DELETE ON TABLES TO betting_user;
+ALTER DEFAULT PRIVILEGES FOR ROLE markaronin IN SCHEMA betting GRANT USAGE ON SEQUENCES TO betting_user;
@@ -11,6 +11,8 @@ s3_bucket=$1
 cargo lambda build --release
 
 lambda_name=betting
+# Everything else the lambda needs has a default or comes from Secrets Manager
+db_host=terraform-20230820160825878800000001.cxp0he9jcakq.us-east-1.rds.amazonaws.com
 output_path="./target/lambda/$lambda_name/bootstrap"
 
 temp_dir=$(mktemp -d)
@@ -26,6 +28,12 @@ aws s3 cp "${zip_file_path}" "${s3_path}"
 aws lambda update-function-code --function-name "${lambda_name}" --s3-bucket "${s3_bucket}" --s3-key "${s3_file_name}" > /dev/null
 aws s3 rm "${s3_path}"
 
+# Merged into the existing environment, since this replaces all of it
+aws lambda wait function-updated --function-name "${lambda_name}"
+environment=$(aws lambda get-function-configuration --function-name "${lambda_name}" --query "Environment.Variables" --output json \
+  | jq --arg db_host "${db_host}" '{Variables: ((. // {}) + {DB_HOST: $db_host})}')
+aws lambda update-function-configuration --function-name "${lambda_name}" --environment "${environment}" > /dev/null
+
 rm -r $temp_dir
 
 echo "Finished uploading $lambda_name"
\ No newline at end of file
@@ -0,0 +1,25 @@
+-- Free-text logs are replaced by structured events. Old logs are kept as Legacy events
+CREATE TABLE IF NOT EXISTS betting.events (
+   id CHAR(36) PRIMARY KEY,
+   created_at timestamptz NOT NULL DEFAULT now(),
+   kind TEXT NOT NULL,
+   actor_id CHAR(36) REFERENCES betting.users(id) ON DELETE SET NULL,
+   -- Not a foreign key because bets are deleted when they're resolved, but their events should stay
+   bet_id CHAR(36),
+   payload JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_created_at_id_idx ON betting.events (created_at, id);
+CREATE INDEX IF NOT EXISTS events_actor_id_created_at_idx ON betting.events (actor_id, created_at);
+CREATE INDEX IF NOT EXISTS events_bet_id_created_at_idx ON betting.events (bet_id, created_at);
+
+DO $$
+BEGIN
+   IF EXISTS (SELECT FROM information_schema.tables WHERE table_schema = 'betting' AND table_name = 'logs') THEN
+      INSERT INTO betting.events (id, created_at, kind, payload)
+      SELECT gen_random_uuid(), created_at, 'Legacy', jsonb_build_object('kind', 'Legacy', 'content', content)
+      FROM betting.logs;
+
+      DROP TABLE betting.logs;
+   END IF;
+END
+$$;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS betting.payouts (
+   -- Not a foreign key because bets are deleted when they're resolved
+   bet_id CHAR(36) NOT NULL,
+   user_id CHAR(36) REFERENCES betting.users(id) ON DELETE CASCADE NOT NULL,
+   bet_name TEXT NOT NULL,
+   result TEXT NOT NULL,
+   spent DOUBLE PRECISION NOT NULL,
+   payout DOUBLE PRECISION NOT NULL,
+   resolved_at timestamptz NOT NULL DEFAULT now(),
+   PRIMARY KEY (bet_id, user_id)
+);
+CREATE INDEX IF NOT EXISTS events_kind_bet_id_idx ON betting.events (kind, bet_id);
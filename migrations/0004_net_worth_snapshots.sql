@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS betting.net_worth_snapshots (
+   user_id CHAR(36) REFERENCES betting.users(id) ON DELETE CASCADE NOT NULL,
+   taken_at timestamptz NOT NULL,
+   net_worth DOUBLE PRECISION NOT NULL,
+   PRIMARY KEY (user_id, taken_at)
+);
+CREATE INDEX IF NOT EXISTS net_worth_snapshots_taken_at_idx ON betting.net_worth_snapshots (taken_at);
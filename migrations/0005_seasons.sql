@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS betting.seasons (
+   id SERIAL PRIMARY KEY,
+   "name" TEXT NOT NULL,
+   started_at timestamptz NOT NULL,
+   ended_at timestamptz,
+   starting_money DOUBLE PRECISION
+);
+-- Only one season can be running at a time
+CREATE UNIQUE INDEX IF NOT EXISTS seasons_expr_idx ON betting.seasons ((ended_at IS NULL)) WHERE ended_at IS NULL;
+CREATE TABLE IF NOT EXISTS betting.season_standings (
+   season_id INTEGER REFERENCES betting.seasons(id) ON DELETE CASCADE NOT NULL,
+   user_id CHAR(36) REFERENCES betting.users(id) ON DELETE CASCADE NOT NULL,
+   user_name TEXT NOT NULL,
+   "rank" INTEGER NOT NULL,
+   liquid_money DOUBLE PRECISION NOT NULL,
+   expected_money DOUBLE PRECISION NOT NULL,
+   max_money DOUBLE PRECISION NOT NULL,
+   PRIMARY KEY (season_id, user_id)
+);
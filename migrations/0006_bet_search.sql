@@ -0,0 +1,8 @@
+-- Descriptions, tags and closing times, and full-text search over names and descriptions
+ALTER TABLE betting.bets
+   ADD COLUMN IF NOT EXISTS description TEXT NOT NULL DEFAULT '',
+   ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}',
+   ADD COLUMN IF NOT EXISTS closes_at timestamptz,
+   ADD COLUMN IF NOT EXISTS search_vector tsvector GENERATED ALWAYS AS (to_tsvector('english', "name" || ' ' || description)) STORED;
+CREATE INDEX IF NOT EXISTS bets_tags_idx ON betting.bets USING GIN (tags);
+CREATE INDEX IF NOT EXISTS bets_search_vector_idx ON betting.bets USING GIN (search_vector);
@@ -0,0 +1 @@
+ALTER TABLE betting.bets ADD COLUMN IF NOT EXISTS resolution_criteria TEXT NOT NULL DEFAULT '';
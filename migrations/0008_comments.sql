@@ -0,0 +1,13 @@
+ALTER TABLE betting.users ADD COLUMN IF NOT EXISTS moderator BOOLEAN NOT NULL DEFAULT false;
+CREATE TABLE IF NOT EXISTS betting.comments (
+   id CHAR(36) PRIMARY KEY,
+   bet_id CHAR(36) REFERENCES betting.bets(id) ON DELETE CASCADE NOT NULL,
+   parent_id CHAR(36) REFERENCES betting.comments(id) ON DELETE CASCADE,
+   author_id CHAR(36) REFERENCES betting.users(id) ON DELETE CASCADE NOT NULL,
+   content TEXT NOT NULL,
+   created_at timestamptz NOT NULL DEFAULT now(),
+   edited_at timestamptz,
+   deleted BOOLEAN NOT NULL DEFAULT false,
+   hidden_by CHAR(36) REFERENCES betting.users(id) ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS comments_bet_id_created_at_idx ON betting.comments (bet_id, created_at);
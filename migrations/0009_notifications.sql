@@ -0,0 +1,18 @@
+ALTER TABLE betting.users
+   ADD COLUMN IF NOT EXISTS email TEXT,
+   ADD COLUMN IF NOT EXISTS email_notifications BOOLEAN NOT NULL DEFAULT false;
+CREATE TABLE IF NOT EXISTS betting.notifications (
+   id CHAR(36) PRIMARY KEY,
+   user_id CHAR(36) REFERENCES betting.users(id) ON DELETE CASCADE NOT NULL,
+   created_at timestamptz NOT NULL DEFAULT now(),
+   kind TEXT NOT NULL,
+   -- Not a foreign key because bets are deleted when they're resolved
+   bet_id CHAR(36),
+   message TEXT NOT NULL,
+   link TEXT NOT NULL,
+   read_at timestamptz,
+   -- For notifications that should only be sent once, like a market closing soon
+   dedupe_key TEXT,
+   UNIQUE (user_id, dedupe_key)
+);
+CREATE INDEX IF NOT EXISTS notifications_user_id_created_at_idx ON betting.notifications (user_id, created_at);
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS betting.webhooks (
+   id CHAR(36) PRIMARY KEY,
+   url TEXT NOT NULL,
+   -- Used to sign payloads, so it has to be kept in the clear
+   secret TEXT NOT NULL,
+   -- Empty means every kind
+   event_kinds TEXT[] NOT NULL DEFAULT '{}',
+   created_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE TABLE IF NOT EXISTS betting.webhook_deliveries (
+   id CHAR(36) PRIMARY KEY,
+   webhook_id CHAR(36) REFERENCES betting.webhooks(id) ON DELETE CASCADE NOT NULL,
+   event_id CHAR(36) REFERENCES betting.events(id) ON DELETE CASCADE NOT NULL,
+   body JSONB NOT NULL,
+   created_at timestamptz NOT NULL DEFAULT now(),
+   attempts INTEGER NOT NULL DEFAULT 0,
+   -- NULL once it's been delivered or given up on
+   next_attempt_at timestamptz DEFAULT now(),
+   delivered_at timestamptz,
+   last_status INTEGER,
+   last_error TEXT
+);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_next_attempt_at_idx ON betting.webhook_deliveries (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS webhook_deliveries_created_at_idx ON betting.webhook_deliveries (created_at);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS betting.slack_users (
+   team_id TEXT NOT NULL,
+   slack_user_id TEXT NOT NULL,
+   user_id CHAR(36) REFERENCES betting.users(id) ON DELETE CASCADE NOT NULL,
+   PRIMARY KEY (team_id, slack_user_id)
+);
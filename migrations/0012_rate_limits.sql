@@ -0,0 +1,7 @@
+-- Requests counted per fixed window, so that limits hold across lambda instances
+CREATE TABLE IF NOT EXISTS betting.rate_limits (
+   key TEXT NOT NULL,
+   window_start BIGINT NOT NULL,
+   count INTEGER NOT NULL,
+   PRIMARY KEY (key, window_start)
+);
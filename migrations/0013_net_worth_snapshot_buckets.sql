@@ -0,0 +1,4 @@
+-- The hour each regular snapshot belongs to, so that two leaderboard loads at once can't both take
+-- one. NULL for the extra snapshots taken when seasons start
+ALTER TABLE betting.net_worth_snapshots ADD COLUMN IF NOT EXISTS hourly_bucket BIGINT;
+CREATE UNIQUE INDEX IF NOT EXISTS net_worth_snapshots_hourly_bucket_idx ON betting.net_worth_snapshots (user_id, hourly_bucket);
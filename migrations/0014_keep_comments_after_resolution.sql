@@ -0,0 +1,3 @@
+-- Resolving a market deletes it, and its discussion should outlive it like its events and payouts
+-- do, so bet_id is no longer a foreign key
+ALTER TABLE betting.comments DROP CONSTRAINT IF EXISTS comments_bet_id_fkey;
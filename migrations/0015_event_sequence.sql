@@ -0,0 +1,14 @@
+-- The order events were committed in, for polling. created_at is when the transaction started, so
+-- two trades can commit in the opposite order to their timestamps. Events are only inserted while
+-- holding a lock that's kept until commit, so a later seq is always committed later
+CREATE SEQUENCE IF NOT EXISTS betting.events_seq_seq;
+ALTER TABLE betting.events ADD COLUMN IF NOT EXISTS seq BIGINT;
+UPDATE betting.events SET seq = ordered.seq
+FROM (SELECT id, row_number() OVER (ORDER BY created_at, id) AS seq FROM betting.events) ordered
+WHERE events.id = ordered.id AND events.seq IS NULL;
+SELECT setval('betting.events_seq_seq', COALESCE((SELECT max(seq) FROM betting.events), 0) + 1, false);
+ALTER TABLE betting.events
+   ALTER COLUMN seq SET DEFAULT nextval('betting.events_seq_seq'),
+   ALTER COLUMN seq SET NOT NULL;
+ALTER SEQUENCE betting.events_seq_seq OWNED BY betting.events.seq;
+CREATE UNIQUE INDEX IF NOT EXISTS events_seq_idx ON betting.events (seq);
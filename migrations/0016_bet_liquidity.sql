@@ -0,0 +1,20 @@
+-- The money a market's creator put in to fund its pools. It used to be tracked as a pair of zero-share
+-- user_bets, which the creator's own trades in the market were then added to
+ALTER TABLE betting.bets ADD COLUMN IF NOT EXISTS liquidity DOUBLE PRECISION;
+UPDATE betting.bets SET liquidity = COALESCE(
+   (SELECT (payload->>'starting_money')::DOUBLE PRECISION FROM betting.events
+      WHERE events.bet_id = bets.id AND events.kind = 'MarketCreated' LIMIT 1),
+   -- Markets from before events only have the creator's user_bets to go on, and buying only adds to those
+   (SELECT 2 * min(spent) FROM betting.user_bets
+      WHERE user_bets.bet_id = bets.id AND user_bets.user_id = bets.creator_id),
+   0
+);
+ALTER TABLE betting.bets ALTER COLUMN liquidity SET NOT NULL;
+
+-- What's left of the creator's user_bets is what they spent trading
+UPDATE betting.user_bets SET spent = GREATEST(user_bets.spent - bets.liquidity / 2, 0)
+FROM betting.bets
+WHERE user_bets.bet_id = bets.id AND user_bets.user_id = bets.creator_id;
+DELETE FROM betting.user_bets USING betting.bets
+WHERE user_bets.bet_id = bets.id AND user_bets.user_id = bets.creator_id
+   AND user_bets.amount = 0 AND user_bets.spent < 0.000001;
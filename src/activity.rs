@@ -0,0 +1,188 @@
+use axum::{
+    extract::{Query, State},
+    response::Html,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{NaiveDate, NaiveTime};
+
+use crate::{
+    comments,
+    error::{AppError, AppResult},
+    model::{Bet, Event, EventCursor, EventFilter, EventPayload, User},
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+const PAGE_SIZE: usize = 50;
+
+/// Query parameters shared by the activity page and API. Empty strings are treated as missing, because
+/// that's what HTML forms send for inputs that weren't filled in
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ActivityQuery {
+    #[serde(default)]
+    user_id: String,
+    #[serde(default)]
+    bet_id: String,
+    #[serde(default)]
+    kind: String,
+    /// Inclusive, YYYY-MM-DD
+    #[serde(default)]
+    from: String,
+    /// Inclusive, YYYY-MM-DD
+    #[serde(default)]
+    to: String,
+    #[serde(default)]
+    cursor: String,
+}
+impl ActivityQuery {
+    fn to_filter(&self) -> Result<EventFilter, String> {
+        let non_empty = |value: &String| (!value.is_empty()).then(|| value.clone());
+        let parse_date = |value: &String| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map_err(|_| format!("\"{value}\" is not a valid date (expected YYYY-MM-DD)"))
+        };
+
+        Ok(EventFilter {
+            actor_id: non_empty(&self.user_id),
+            bet_id: non_empty(&self.bet_id),
+            kind: match non_empty(&self.kind) {
+                Some(kind) if !EventPayload::KINDS.contains(&kind.as_str()) => {
+                    return Err(format!("\"{kind}\" is not a kind of activity"))
+                }
+                kind => kind,
+            },
+            from: match non_empty(&self.from) {
+                Some(from) => Some(parse_date(&from)?.and_time(NaiveTime::MIN).and_utc()),
+                None => None,
+            },
+            to: match non_empty(&self.to) {
+                Some(to) => Some(
+                    parse_date(&to)?
+                        .succ_opt()
+                        .ok_or("Date is too far in the future")?
+                        .and_time(NaiveTime::MIN)
+                        .and_utc(),
+                ),
+                None => None,
+            },
+        })
+    }
+
+    fn to_cursor(&self) -> Result<Option<EventCursor>, String> {
+        if self.cursor.is_empty() {
+            Ok(None)
+        } else {
+            EventCursor::parse(&self.cursor)
+                .map(Some)
+                .ok_or_else(|| "Invalid cursor".to_string())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ActivityEntry {
+    id: String,
+    created_at_seconds: i64,
+    kind: &'static str,
+    actor_id: Option<String>,
+    actor_name: Option<String>,
+    bet_id: Option<String>,
+    description: String,
+    payload: EventPayload,
+}
+impl From<Event> for ActivityEntry {
+    fn from(event: Event) -> Self {
+        Self {
+            created_at_seconds: event.created_at.timestamp(),
+            kind: event.payload.kind(),
+            description: event.describe(),
+            id: event.id,
+            actor_id: event.actor_id,
+            actor_name: event.actor_name,
+            bet_id: event.bet_id,
+            payload: event.payload.0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ActivityPage {
+    entries: Vec<ActivityEntry>,
+    next_cursor: Option<String>,
+}
+
+async fn get_activity_page(app_state: &AppState, query: &ActivityQuery) -> AppResult<ActivityPage> {
+    let filter = query.to_filter().map_err(AppError::Validation)?;
+    let cursor = query.to_cursor().map_err(AppError::Validation)?;
+
+    // Fetch one extra to find out whether there's another page after this one
+    let mut events =
+        Event::list_filtered(&app_state.db, &filter, cursor.as_ref(), PAGE_SIZE + 1).await?;
+    let next_cursor = if events.len() > PAGE_SIZE {
+        events.truncate(PAGE_SIZE);
+        events
+            .last()
+            .map(|event| EventCursor::after(event).to_string())
+    } else {
+        None
+    };
+
+    Ok(ActivityPage {
+        entries: events.into_iter().map(ActivityEntry::from).collect(),
+        next_cursor,
+    })
+}
+
+pub async fn activity_api(
+    ExtractUserId(_user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Query(query): Query<ActivityQuery>,
+) -> AppResult<Json<ActivityPage>> {
+    Ok(Json(get_activity_page(&app_state, &query).await?))
+}
+
+pub async fn activity(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Query(query): Query<ActivityQuery>,
+) -> AppResult<Html<String>> {
+    let page = get_activity_page(&app_state, &query).await?;
+    let mut context = tera::Context::new();
+
+    // Keep the same filters when going to the next page
+    let next_page_query = page.next_cursor.as_ref().map(|next_cursor| {
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("user_id", &query.user_id)
+            .append_pair("bet_id", &query.bet_id)
+            .append_pair("kind", &query.kind)
+            .append_pair("from", &query.from)
+            .append_pair("to", &query.to)
+            .append_pair("cursor", next_cursor)
+            .finish()
+    });
+
+    let mut users = User::list(&app_state.db).await?;
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    let Some(viewer) = users.iter().find(|user| user.id == user_id) else {
+        return Err(AppError::user_not_found());
+    };
+
+    // Resolved markets are deleted along with their page, so this is where their discussion is shown
+    let resolved_discussion = match query.bet_id.as_str() {
+        "" => None,
+        bet_id if Bet::get_by_id(&app_state.db, bet_id).await?.is_none() => {
+            Some(comments::list_thread(&app_state, bet_id, viewer).await?)
+        }
+        _ => None,
+    };
+
+    context.insert("entries", &page.entries);
+    context.insert("next_page_query", &next_page_query);
+    context.insert("users", &users);
+    context.insert("kinds", &EventPayload::KINDS);
+    context.insert("query", &query);
+    context.insert("resolved_discussion", &resolved_discussion);
+
+    Ok(Html(app_state.engine.render("activity", &context)?))
+}
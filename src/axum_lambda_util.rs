@@ -1,41 +1,127 @@
+use std::{future::IntoFuture, net::SocketAddr, str::FromStr, time::Duration};
+
 use axum::Router;
-use http::{
-    header::{AUTHORIZATION, CONTENT_TYPE},
-    request, HeaderValue, Method,
-};
+use envconfig::Envconfig;
+use http::{request, HeaderName, HeaderValue, Method};
+use tokio::signal::unix::{signal, SignalKind};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
+use crate::background::Background;
+
+/// Where the site is and how to serve it
+#[derive(Envconfig)]
+pub struct ServerConfig {
+    /// The public address of the site, for links in emails and chat messages. Cookies are HTTPS only
+    /// if it's https://, so it has to be set everywhere but lambda, which is production
+    #[envconfig(from = "SITE_URL")]
+    pub site_url: Option<String>,
+    /// Comma separated origins that browsers can make requests from, where * matches anything (e.g.
+    /// http://localhost:*). Defaults to the site's own origin on lambda and any localhost port
+    /// everywhere else
+    #[envconfig(from = "CORS_ALLOWED_ORIGINS")]
+    pub cors_allowed_origins: Option<String>,
+    /// Comma separated. The routes only use GET and POST
+    #[envconfig(from = "CORS_ALLOWED_METHODS", default = "GET,POST")]
+    pub cors_allowed_methods: String,
+    /// Comma separated
+    #[envconfig(from = "CORS_ALLOWED_HEADERS", default = "authorization,content-type")]
+    pub cors_allowed_headers: String,
+    /// Only used off lambda. 0.0.0.0:8080 accepts connections from other machines
+    #[envconfig(from = "LISTEN_ADDR", default = "127.0.0.1:8080")]
+    pub listen_addr: SocketAddr,
+    /// How long in-flight requests, and then background work, get to finish after SIGTERM
+    #[envconfig(from = "SHUTDOWN_TIMEOUT_SECONDS", default = "30")]
+    pub shutdown_timeout_seconds: u64,
+}
+
+/// Where the lambda is served from
+const PRODUCTION_SITE_URL: &str = "https://betting.markaronin.com";
+
 pub fn is_running_on_lambda() -> bool {
     std::env::var("AWS_LAMBDA_RUNTIME_API").is_ok()
 }
 
-fn get_default_cors_policy() -> CorsLayer {
-    CorsLayer::new()
-        .allow_origin(AllowOrigin::predicate(
-            |origin: &HeaderValue, _request_parts: &request::Parts| {
-                (is_running_on_lambda() && origin.as_bytes().ends_with(b"betting.markaronin.com"))
-                    || (!is_running_on_lambda()
-                        && origin.as_bytes().starts_with(b"http://localhost:"))
-            },
-        ))
-        .allow_methods([
-            Method::CONNECT,
-            Method::DELETE,
-            Method::GET,
-            Method::HEAD,
-            Method::OPTIONS,
-            Method::PATCH,
-            Method::POST,
-            Method::PUT,
-            Method::TRACE,
-        ])
-        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
-        .allow_credentials(true)
-}
-
-pub async fn run_router(router: Router) {
-    let router = router.layer(get_default_cors_policy());
+fn comma_separated(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Whether `origin` matches `pattern`, where a * in the pattern matches anything
+fn origin_matches(pattern: &str, origin: &[u8]) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            origin.len() >= prefix.len() + suffix.len()
+                && origin.starts_with(prefix.as_bytes())
+                && origin.ends_with(suffix.as_bytes())
+        }
+        None => origin == pattern.as_bytes(),
+    }
+}
+
+impl ServerConfig {
+    /// The site's own origin, which is SITE_URL without any trailing slash. Errors if it isn't set
+    /// off lambda, since guessing wrong would either break cookies or send them unencrypted
+    pub fn site_origin(&self) -> Result<&str, String> {
+        match &self.site_url {
+            Some(site_url) => Ok(site_url.trim_end_matches('/')),
+            None if is_running_on_lambda() => Ok(PRODUCTION_SITE_URL),
+            None => Err(
+                "SITE_URL has to be set to where the site is served, e.g. http://localhost:8080"
+                    .to_string(),
+            ),
+        }
+    }
 
+    /// Errors with a message for the startup logs if a method or header isn't valid
+    pub fn cors_policy(&self) -> Result<CorsLayer, String> {
+        let origins = match &self.cors_allowed_origins {
+            Some(origins) => comma_separated(origins).map(str::to_string).collect(),
+            None if is_running_on_lambda() => {
+                self.site_origin().into_iter().map(str::to_string).collect()
+            }
+            None => vec!["http://localhost:*".to_string()],
+        };
+        let methods = comma_separated(&self.cors_allowed_methods)
+            .map(|method| {
+                Method::from_str(&method.to_uppercase())
+                    .map_err(|_| format!("Invalid CORS_ALLOWED_METHODS entry \"{method}\""))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = comma_separated(&self.cors_allowed_headers)
+            .map(|header| {
+                HeaderName::from_str(header)
+                    .map_err(|_| format!("Invalid CORS_ALLOWED_HEADERS entry \"{header}\""))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(
+                move |origin: &HeaderValue, _request_parts: &request::Parts| {
+                    origins
+                        .iter()
+                        .any(|pattern| origin_matches(pattern, origin.as_bytes()))
+                },
+            ))
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(true))
+    }
+}
+
+/// Resolves on SIGTERM (what systemd and container runtimes send) or Ctrl+C
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Serves until shut down. Off lambda, shutting down stops accepting connections, ends live update
+/// streams and waits for the requests already being handled, so trades in progress either commit or
+/// aren't started. Then it waits for the background work, like emails those trades sent
+pub async fn run_router(router: Router, config: &ServerConfig, background: &Background) {
     if is_running_on_lambda() {
         // To run with AWS Lambda runtime, wrap in our `LambdaLayer`
         let app = tower::ServiceBuilder::new()
@@ -44,8 +130,87 @@ pub async fn run_router(router: Router) {
 
         lambda_http::run(app).await.unwrap();
     } else {
-        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 8080));
-        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-        axum::serve(listener, router).await.unwrap();
+        let listener = tokio::net::TcpListener::bind(config.listen_addr)
+            .await
+            .unwrap();
+        log::info!("Listening on {}", config.listen_addr);
+
+        // Connection info is where the rate limiter gets IP addresses from
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        let shutting_down = background.clone();
+        let server = axum::serve(listener, service).with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            log::info!("Shutting down once in-flight requests finish");
+            shutting_down.start_shutdown();
+        });
+        let timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+        let shutdown_started = background.shutdown_started();
+        tokio::select! {
+            result = server.into_future() => result.unwrap(),
+            _ = async {
+                shutdown_started.await;
+                tokio::time::sleep(timeout).await;
+            } => log::warn!("Gave up waiting for requests to finish after {timeout:?}"),
+        }
+        background.drain(timeout).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(methods: &str, headers: &str) -> ServerConfig {
+        ServerConfig {
+            site_url: None,
+            cors_allowed_origins: Some("https://example.com".to_string()),
+            cors_allowed_methods: methods.to_string(),
+            cors_allowed_headers: headers.to_string(),
+            listen_addr: "127.0.0.1:8080".parse().unwrap(),
+            shutdown_timeout_seconds: 30,
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_cors_entries() {
+        assert!(config("get, POST", "content-type").cors_policy().is_ok());
+        assert_eq!(
+            config("GET,PO ST", "content-type")
+                .cors_policy()
+                .unwrap_err(),
+            "Invalid CORS_ALLOWED_METHODS entry \"PO ST\""
+        );
+        assert_eq!(
+            config("GET", "content type").cors_policy().unwrap_err(),
+            "Invalid CORS_ALLOWED_HEADERS entry \"content type\""
+        );
+    }
+
+    #[test]
+    fn matches_origins() {
+        assert!(origin_matches(
+            "http://localhost:*",
+            b"http://localhost:8080"
+        ));
+        assert!(!origin_matches(
+            "http://localhost:*",
+            b"http://localhost.evil.com"
+        ));
+        assert!(origin_matches(
+            "https://*.example.com",
+            b"https://betting.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            b"https://evilexample.com"
+        ));
+        assert!(origin_matches(
+            "https://example.com",
+            b"https://example.com"
+        ));
+        assert!(!origin_matches(
+            "https://example.com",
+            b"https://example.com.evil.com"
+        ));
     }
 }
@@ -0,0 +1,74 @@
+use std::{future::Future, time::Duration};
+
+use tokio::sync::watch;
+use tokio_util::task::TaskTracker;
+
+/// Work that carries on outside of requests, like sending emails and webhooks. Shutting down tells
+/// the loops and live update streams to stop, and then waits for whatever is still running
+#[derive(Clone)]
+pub struct Background {
+    tasks: TaskTracker,
+    shutting_down: watch::Sender<bool>,
+}
+impl Default for Background {
+    fn default() -> Self {
+        let (shutting_down, _) = watch::channel(false);
+        Self {
+            tasks: TaskTracker::new(),
+            shutting_down,
+        }
+    }
+}
+impl Background {
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.spawn(task);
+    }
+
+    /// True once shutdown has started
+    pub fn shutting_down(&self) -> watch::Receiver<bool> {
+        self.shutting_down.subscribe()
+    }
+
+    /// Resolves once shutdown has started, for loops to stop on
+    pub fn shutdown_started(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut shutting_down = self.shutting_down();
+        async move {
+            let _ = shutting_down.wait_for(|started| *started).await;
+        }
+    }
+
+    pub fn start_shutdown(&self) {
+        self.shutting_down.send_replace(true);
+    }
+
+    /// Waits up to `timeout` for everything that was spawned to finish
+    pub async fn drain(&self, timeout: Duration) {
+        self.tasks.close();
+        if tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            log::warn!("Gave up waiting for background work to finish after {timeout:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn draining_waits_for_loops_to_stop() {
+        let background = Background::default();
+        let stopped = background.shutdown_started();
+        let (finished, mut finished_receiver) = watch::channel(false);
+        background.spawn(async move {
+            stopped.await;
+            finished.send_replace(true);
+        });
+
+        background.start_shutdown();
+        background.drain(Duration::from_secs(5)).await;
+        assert!(*finished_receiver.borrow_and_update());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use axum::{extract::State, response::Redirect, Form};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{AppError, AppResult},
+    markdown,
+    model::{Bet, Comment, Event, EventPayload, Notification, User, UserBet},
+    notifications,
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+const MAX_COMMENT_LENGTH: usize = 10_000;
+
+/// Replies stop being indented past this depth, so that long back-and-forths stay readable
+const MAX_DISPLAY_DEPTH: usize = 6;
+
+/// A comment as shown on the market page, in thread order
+#[derive(Serialize)]
+pub struct CommentView {
+    id: String,
+    author_id: String,
+    author_name: String,
+    content: String,
+    content_html: String,
+    created_at_seconds: i64,
+    edited: bool,
+    deleted: bool,
+    hidden: bool,
+    depth: usize,
+    /// The author's current holdings in this market
+    yes_shares: usize,
+    no_shares: usize,
+    can_edit: bool,
+    can_moderate: bool,
+}
+
+/// Every comment on a market, flattened so that replies come straight after what they're replying to.
+/// Works for resolved markets too, whose comments outlive them
+pub async fn list_thread(
+    app_state: &AppState,
+    bet_id: &str,
+    viewer: &User,
+) -> sqlx::Result<Vec<CommentView>> {
+    let comments = Comment::list_by_bet_id(&app_state.db, bet_id).await?;
+
+    let shares = UserBet::list_by_bet_id(&app_state.db, bet_id)
+        .await?
+        .into_iter()
+        .map(|user_bet| ((user_bet.user_id, user_bet.is_yes), user_bet.amount))
+        .collect::<HashMap<(String, bool), usize>>();
+
+    let mut replies = HashMap::<Option<&str>, Vec<&Comment>>::new();
+    for comment in comments.iter() {
+        replies
+            .entry(comment.parent_id.as_deref())
+            .or_default()
+            .push(comment);
+    }
+
+    let mut thread = vec![];
+    // Depth first, so each comment is followed by its replies. Children are pushed in reverse so
+    // that they come off the stack oldest first
+    let mut stack: Vec<(&Comment, usize)> = replies
+        .get(&None)
+        .map(|top_level| {
+            top_level
+                .iter()
+                .rev()
+                .map(|comment| (*comment, 0))
+                .collect()
+        })
+        .unwrap_or_default();
+    while let Some((comment, depth)) = stack.pop() {
+        let hidden = comment.hidden_by.is_some();
+        // Moderators and authors can still see hidden comments, so that they know what was hidden
+        let visible = !comment.deleted
+            && (!hidden || viewer.is_moderator() || comment.author_id == viewer.id);
+
+        thread.push(CommentView {
+            id: comment.id.clone(),
+            author_id: comment.author_id.clone(),
+            author_name: comment.author_name.clone().unwrap_or_default(),
+            content: if visible {
+                comment.content.clone()
+            } else {
+                String::new()
+            },
+            content_html: if visible {
+                markdown::render(&comment.content)
+            } else {
+                String::new()
+            },
+            created_at_seconds: comment.created_at.timestamp(),
+            edited: comment.edited_at.is_some(),
+            deleted: comment.deleted,
+            hidden,
+            depth: depth.min(MAX_DISPLAY_DEPTH),
+            yes_shares: shares
+                .get(&(comment.author_id.clone(), true))
+                .copied()
+                .unwrap_or(0),
+            no_shares: shares
+                .get(&(comment.author_id.clone(), false))
+                .copied()
+                .unwrap_or(0),
+            can_edit: !comment.deleted && comment.author_id == viewer.id,
+            can_moderate: !comment.deleted && viewer.is_moderator(),
+        });
+
+        if let Some(children) = replies.get(&Some(comment.id.as_str())) {
+            stack.extend(children.iter().rev().map(|child| (*child, depth + 1)));
+        }
+    }
+
+    Ok(thread)
+}
+
+fn validate_content(content: &str) -> Result<&str, String> {
+    let content = content.trim();
+    if content.is_empty() {
+        Err("Comments can't be empty".to_string())
+    } else if content.chars().count() > MAX_COMMENT_LENGTH {
+        Err(format!(
+            "Comments can be at most {MAX_COMMENT_LENGTH} characters"
+        ))
+    } else {
+        Ok(content)
+    }
+}
+
+fn redirect_to_comment(bet_id: &str, comment_id: &str) -> Redirect {
+    Redirect::to(&format!("/bet/{bet_id}#comment-{comment_id}"))
+}
+
+#[derive(Deserialize)]
+pub struct PostCommentRequest {
+    bet_id: String,
+    /// Empty for top-level comments
+    #[serde(default)]
+    parent_id: String,
+    content: String,
+}
+pub async fn post_comment(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<PostCommentRequest>,
+) -> AppResult<Redirect> {
+    let content = validate_content(&request.content).map_err(AppError::Validation)?;
+
+    let mut tx = app_state.db.begin().await?;
+
+    let Some(bet) = Bet::get_for_update_by_id(&mut tx, &request.bet_id).await? else {
+        return Err(AppError::NotFound("No such market".to_string()));
+    };
+    let Some(user) = User::get_for_update_by_id(&mut tx, &user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+
+    let parent = match request.parent_id.as_str() {
+        "" => None,
+        parent_id => match Comment::get_for_update_by_id(&mut tx, parent_id).await? {
+            Some(parent) if parent.bet_id == bet.id => Some(parent),
+            _ => return Err(AppError::NotFound("No such comment".to_string())),
+        },
+    };
+
+    let comment = Comment::insert(
+        &mut tx,
+        &bet.id,
+        parent.as_ref().map(|parent| parent.id.as_str()),
+        &user.id,
+        content,
+    )
+    .await?;
+
+    let event = Event::insert(
+        &mut tx,
+        Some(&user),
+        Some(&bet.id),
+        EventPayload::CommentPosted {
+            bet_name: bet.name.clone(),
+            comment_id: comment.id.clone(),
+            reply_to_user_id: parent.as_ref().map(|parent| parent.author_id.clone()),
+        },
+    )
+    .await?;
+
+    let notifications = match &parent {
+        Some(parent) => {
+            Notification::insert_all(
+                &mut tx,
+                &notifications::comment_reply(&bet, &comment.id, &user, &parent.author_id),
+            )
+            .await?
+        }
+        None => vec![],
+    };
+
+    tx.commit().await?;
+
+    app_state.live_updates.publish_event(&event);
+    notifications::send_emails(&app_state, notifications);
+
+    Ok(redirect_to_comment(&bet.id, &comment.id))
+}
+
+#[derive(Deserialize)]
+pub struct EditCommentRequest {
+    comment_id: String,
+    content: String,
+}
+pub async fn edit_comment(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<EditCommentRequest>,
+) -> AppResult<Redirect> {
+    let content = validate_content(&request.content).map_err(AppError::Validation)?;
+
+    let mut tx = app_state.db.begin().await?;
+
+    match Comment::get_for_update_by_id(&mut tx, &request.comment_id).await? {
+        Some(comment) if comment.author_id == user_id && !comment.deleted => {
+            Comment::update_content(&mut tx, &comment.id, content).await?;
+
+            tx.commit().await?;
+
+            Ok(redirect_to_comment(&comment.bet_id, &comment.id))
+        }
+        _ => Err(AppError::NotFound("No such comment".to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteCommentRequest {
+    comment_id: String,
+}
+pub async fn delete_comment(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<DeleteCommentRequest>,
+) -> AppResult<Redirect> {
+    let mut tx = app_state.db.begin().await?;
+
+    match Comment::get_for_update_by_id(&mut tx, &request.comment_id).await? {
+        Some(comment) if comment.author_id == user_id && !comment.deleted => {
+            Comment::delete(&mut tx, &comment.id).await?;
+
+            tx.commit().await?;
+
+            Ok(redirect_to_comment(&comment.bet_id, &comment.id))
+        }
+        _ => Err(AppError::NotFound("No such comment".to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HideCommentRequest {
+    comment_id: String,
+    /// False to unhide
+    hidden: bool,
+}
+pub async fn hide_comment(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<HideCommentRequest>,
+) -> AppResult<Redirect> {
+    let mut tx = app_state.db.begin().await?;
+
+    // Only moderators can hide comments, and for anybody else it looks like there's nothing there
+    let user = match User::get_for_update_by_id(&mut tx, &user_id).await? {
+        Some(user) if user.is_moderator() => user,
+        _ => return Err(AppError::NotFound("No such comment".to_string())),
+    };
+
+    match Comment::get_for_update_by_id(&mut tx, &request.comment_id).await? {
+        Some(comment) => {
+            Comment::set_hidden_by(
+                &mut tx,
+                &comment.id,
+                request.hidden.then_some(user.id.as_str()),
+            )
+            .await?;
+
+            tx.commit().await?;
+
+            Ok(redirect_to_comment(&comment.bet_id, &comment.id))
+        }
+        None => Err(AppError::NotFound("No such comment".to_string())),
+    }
+}
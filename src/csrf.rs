@@ -0,0 +1,146 @@
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use uuid::Uuid;
+
+use crate::{
+    error::{AppError, AppResult},
+    repository::Repository,
+    signing,
+    user_id_cookie::hardened_cookie,
+    AppState,
+};
+
+/// A random value per browser. Forms prove they came from our pages by including it signed, which
+/// other sites can't do since they can neither read the cookie nor sign anything
+pub const COOKIE_NAME: &str = "betting-csrf";
+/// Where forms put the token
+pub const FORM_FIELD: &str = "csrf_token";
+/// Where scripts can put the token instead
+const HEADER_NAME: &str = "x-csrf-token";
+/// Same as axum's default limit for form bodies
+const MAX_FORM_BYTES: usize = 2 * 1024 * 1024;
+/// Posted to by other servers rather than browsers, and checked with their own signatures
+const EXEMPT_PATHS: [&str; 1] = ["/slack/commands"];
+
+#[derive(Clone)]
+struct CsrfNonce(String);
+
+pub fn token_for(secret: &str, nonce: &str) -> String {
+    signing::sign(secret, &format!("csrf:{nonce}"))
+}
+
+/// The token for forms on the page being rendered, as `csrf_token` in the template context
+pub struct CsrfToken(pub String);
+
+#[async_trait]
+impl<R: Repository> FromRequestParts<AppState<R>> for CsrfToken {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState<R>,
+    ) -> Result<Self, Self::Rejection> {
+        let nonce = parts.extensions.get::<CsrfNonce>().ok_or_else(|| {
+            AppError::Internal("CSRF middleware isn't in front of this route".to_string())
+        })?;
+        Ok(CsrfToken(token_for(&state.secret, &nonce.0)))
+    }
+}
+
+fn needs_checking(request: &Request) -> bool {
+    ![Method::GET, Method::HEAD, Method::OPTIONS].contains(request.method())
+        && !EXEMPT_PATHS.contains(&request.uri().path())
+}
+
+/// Finds the token in the header or the form, handing back the request with its body intact
+async fn submitted_token(request: Request) -> AppResult<(Option<String>, Request)> {
+    if let Some(token) = request.headers().get(HEADER_NAME) {
+        let token = token.to_str().ok().map(str::to_string);
+        return Ok((token, request));
+    }
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| {
+            content_type
+                .as_bytes()
+                .starts_with(b"application/x-www-form-urlencoded")
+        });
+    if !is_form {
+        return Ok((None, request));
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_FORM_BYTES)
+        .await
+        .map_err(|_| AppError::Validation("That form is too big".to_string()))?;
+    let token = url::form_urlencoded::parse(&bytes)
+        .find(|(name, _)| name == FORM_FIELD)
+        .map(|(_, token)| token.into_owned());
+    Ok((token, Request::from_parts(parts, Body::from(bytes))))
+}
+
+/// Whether the response is a page, which is the only thing that can have forms on it
+fn is_html(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/html"))
+}
+
+/// Rejects anything but reading unless it has a valid token, and gives browsers a nonce to sign
+/// tokens with if they don't have one yet and are being sent a page
+pub async fn protect<R: Repository>(
+    State(app_state): State<AppState<R>>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    let existing_nonce = jar
+        .get(COOKIE_NAME)
+        .map(|cookie| cookie.value().to_string());
+
+    let mut request = if needs_checking(&request) {
+        let (token, request) = match submitted_token(request).await {
+            Ok(submitted) => submitted,
+            Err(error) => return error.into_response(),
+        };
+        let is_valid = match (&existing_nonce, token) {
+            (Some(nonce), Some(token)) => {
+                signing::verify(&app_state.secret, &format!("csrf:{nonce}"), &token)
+            }
+            _ => false,
+        };
+        if !is_valid {
+            return AppError::Forbidden(
+                "This form is out of date, reload the page and try again".to_string(),
+            )
+            .into_response();
+        }
+        request
+    } else {
+        request
+    };
+
+    let nonce = existing_nonce
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    request.extensions_mut().insert(CsrfNonce(nonce.clone()));
+    let response = next.run(request).await;
+
+    match existing_nonce {
+        None if is_html(&response) => (
+            jar.add(hardened_cookie(COOKIE_NAME, nonce, &app_state.site_url)),
+            response,
+        )
+            .into_response(),
+        _ => response,
+    }
+}
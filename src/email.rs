@@ -0,0 +1,123 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use envconfig::Envconfig;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Address, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Anything that can deliver an email. Boxed futures rather than an async fn so that it can be used
+/// as a trait object
+pub trait EmailSender: Send + Sync {
+    fn send(&self, email: Email) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + '_>>;
+}
+
+pub fn is_valid_address(address: &str) -> bool {
+    address.parse::<Address>().is_ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SmtpSecurity {
+    /// Plaintext, for local mock servers like MailHog
+    None,
+    StartTls,
+    /// TLS from the start of the connection, usually on port 465
+    Tls,
+}
+
+#[derive(Envconfig)]
+struct SmtpConfig {
+    /// Email is turned off entirely if this isn't set
+    #[envconfig(from = "SMTP_HOST")]
+    host: Option<String>,
+    #[envconfig(from = "SMTP_PORT")]
+    port: Option<u16>,
+    #[envconfig(from = "SMTP_USERNAME")]
+    username: Option<String>,
+    /// One of none, starttls or tls
+    #[envconfig(from = "SMTP_SECURITY", default = "starttls")]
+    security: String,
+    #[envconfig(from = "SMTP_FROM", default = "Betting <betting@markaronin.com>")]
+    from: String,
+}
+
+pub struct SmtpEmailSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+impl SmtpEmailSender {
+    /// Configured from SMTP_* environment variables and the password from secrets, or None if
+    /// SMTP_HOST isn't set. Errors are messages saying what's misconfigured, for the startup logs
+    pub fn from_env(password: Option<String>) -> Result<Option<Arc<dyn EmailSender>>, String> {
+        let config = SmtpConfig::init_from_env().map_err(|error| error.to_string())?;
+        let Some(host) = config.host else {
+            return Ok(None);
+        };
+
+        let security = match config.security.to_lowercase().as_str() {
+            "none" => SmtpSecurity::None,
+            "starttls" => SmtpSecurity::StartTls,
+            "tls" => SmtpSecurity::Tls,
+            other => {
+                return Err(format!(
+                    "Unknown SMTP_SECURITY \"{other}\", expected none, starttls or tls"
+                ))
+            }
+        };
+
+        let invalid_host = |error| format!("SMTP_HOST \"{host}\" isn't valid: {error}");
+        let mut builder = match security {
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host).map_err(invalid_host)?
+            }
+            SmtpSecurity::Tls => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&host).map_err(invalid_host)?
+            }
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (config.username, password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        let from = config
+            .from
+            .parse()
+            .map_err(|error| format!("SMTP_FROM \"{}\" isn't valid: {error}", config.from))?;
+
+        log::info!("Sending notification emails through {host} ({security:?})");
+
+        Ok(Some(Arc::new(Self {
+            transport: builder.build(),
+            from,
+        })))
+    }
+}
+impl EmailSender for SmtpEmailSender {
+    fn send(&self, email: Email) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + '_>> {
+        Box::pin(async move {
+            let to: Mailbox = email.to.parse().map_err(|error| format!("{error:?}"))?;
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(email.subject)
+                .body(email.body)
+                .map_err(|error| error.to_string())?;
+
+            self.transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(|error| error.to_string())
+        })
+    }
+}
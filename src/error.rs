@@ -0,0 +1,140 @@
+use std::fmt::Display;
+
+use axum::response::{IntoResponse, Response};
+use http::StatusCode;
+
+use crate::market::TradeError;
+
+/// Everything a request can fail with. The messages are shown to the user, except for internal
+/// errors, which are logged and only show up as "something went wrong"
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    /// The request made sense, but something changed underneath it, like the price of a share
+    Conflict(String),
+    Validation(String),
+    Unauthorized(String),
+    /// Logged in (or not) is fine, but the request didn't come from one of our pages
+    Forbidden(String),
+    Internal(String),
+}
+pub type AppResult<T> = Result<T, AppError>;
+
+impl AppError {
+    /// For users who are logged in but aren't in the database anymore
+    pub fn user_not_found() -> Self {
+        AppError::Unauthorized(
+            "Your account doesn't exist anymore, try logging in again".to_string(),
+        )
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Internal errors are bugs or outages, so they're logged loudly. Everything else is the normal
+    /// result of a bad request
+    pub fn log(&self) {
+        match self {
+            AppError::Internal(detail) => log::error!("{detail}"),
+            _ => log::info!("Rejected request with {}: {self}", self.status()),
+        }
+    }
+}
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::Validation(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message) => f.write_str(message),
+            AppError::Internal(_) => f.write_str("Something went wrong, try again later"),
+        }
+    }
+}
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        self.log();
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+/// The error and everything that caused it, since some errors (like tera's) only describe the
+/// outermost layer
+fn describe(error: &dyn std::error::Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        description.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    description
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        AppError::Internal(format!("Database error: {}", describe(&error)))
+    }
+}
+impl From<tera::Error> for AppError {
+    fn from(error: tera::Error) -> Self {
+        AppError::Internal(format!("Template error: {}", describe(&error)))
+    }
+}
+impl From<TradeError> for AppError {
+    fn from(error: TradeError) -> Self {
+        match error {
+            TradeError::PriceChanged { .. } => AppError::Conflict(error.to_string()),
+            // Creator-only actions look the same as a market that doesn't exist, like editing
+            TradeError::NotCreator => AppError::NotFound("No such market".to_string()),
+            _ => AppError::Validation(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_details_arent_shown_to_users() {
+        let error = AppError::from(sqlx::Error::PoolTimedOut);
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!error.to_string().contains("pool"));
+
+        let error = AppError::Validation("Comments can't be empty".to_string());
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.to_string(), "Comments can't be empty");
+    }
+
+    #[test]
+    fn trade_errors_keep_their_meaning() {
+        assert_eq!(
+            AppError::from(TradeError::NotEnoughMoney).status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            AppError::from(TradeError::NotCreator).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert!(matches!(
+            AppError::from(TradeError::PriceChanged {
+                amount: 10,
+                price: 6.0,
+                limit: 5.0,
+                buying: true
+            }),
+            AppError::Conflict(_)
+        ));
+    }
+}
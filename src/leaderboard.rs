@@ -1,121 +1,518 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
 use axum::{
-    extract::State,
-    response::{Html, IntoResponse},
+    extract::{Query, State},
+    response::Html,
 };
-use serde::Serialize;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    model::{Bet, User, UserBet},
+    error::AppResult,
+    model::{Bet, NetWorthSnapshot, Season, User, UserBet},
     user_id_cookie::ExtractUserId,
     AppState,
 };
 
 #[derive(Debug, Serialize)]
 struct LeaderboardEntry {
+    user_id: String,
     name: String,
     liquid_money: f64,
     expected_money: f64,
     max_money: f64,
+    /// Change in expected money over the selected window
+    expected_money_change: f64,
+    /// SVG polyline points showing expected money over the selected window
+    sparkline: String,
+}
+
+/// How often to record everybody's net worth. Snapshots are taken when the leaderboard is viewed
+/// rather than on a timer, because lambda only runs while handling requests
+const SNAPSHOT_INTERVAL: Duration = Duration::hours(1);
+
+/// Most points to draw in a sparkline, so that long windows don't produce enormous pages
+const MAX_SPARKLINE_POINTS: usize = 60;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardWindow {
+    Week,
+    Month,
+    /// Since the current season started
+    Season,
+    #[default]
+    AllTime,
+}
+impl LeaderboardWindow {
+    const OPTIONS: [(LeaderboardWindow, &'static str); 4] = [
+        (LeaderboardWindow::Week, "This week"),
+        (LeaderboardWindow::Month, "This month"),
+        (LeaderboardWindow::Season, "This season"),
+        (LeaderboardWindow::AllTime, "All time"),
+    ];
+
+    /// Windows never reach back past the start of the current season, because everybody's money was
+    /// reset then and the drop would drown out everything else
+    fn start(
+        &self,
+        now: DateTime<Utc>,
+        season_start: Option<DateTime<Utc>>,
+    ) -> Option<DateTime<Utc>> {
+        let start = match self {
+            LeaderboardWindow::Week => now - Duration::days(7),
+            LeaderboardWindow::Month => now - Duration::days(30),
+            LeaderboardWindow::Season => season_start?,
+            LeaderboardWindow::AllTime => return None,
+        };
+        Some(season_start.map_or(start, |season_start| start.max(season_start)))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardQuery {
+    #[serde(default)]
+    window: LeaderboardWindow,
 }
 
 pub async fn leaderboard(
     ExtractUserId(_user_id): ExtractUserId,
     State(app_state): State<AppState>,
-) -> impl IntoResponse {
-    let bets = Bet::list(&app_state.pool).await;
+    Query(query): Query<LeaderboardQuery>,
+) -> AppResult<Html<String>> {
+    let bets = Bet::list(&app_state.db).await?;
+
+    let users = User::list(&app_state.db).await?;
 
-    let users = User::list(&app_state.pool).await;
+    let user_bets = UserBet::list(&app_state.db).await?;
 
-    let user_bets = UserBet::list(&app_state.pool).await;
+    let now = Utc::now();
+
+    let holdings = Holdings::new(&bets, &user_bets);
+
+    let expected_money_by_user = users
+        .iter()
+        .map(|user| (user.id.clone(), holdings.expected_money(user)))
+        .collect::<BTreeMap<String, f64>>();
+
+    let snapshot_due = match NetWorthSnapshot::latest_taken_at(&app_state.db).await? {
+        Some(latest_taken_at) => now - latest_taken_at >= SNAPSHOT_INTERVAL,
+        None => true,
+    };
+    if snapshot_due {
+        NetWorthSnapshot::insert_hourly(&app_state.db, now, &expected_money_by_user).await?;
+    }
+
+    let current_season = Season::get_current(&app_state.db).await?;
+
+    let window_start = query
+        .window
+        .start(now, current_season.as_ref().map(|season| season.started_at));
+
+    let baselines = match window_start {
+        Some(window_start) => NetWorthSnapshot::list_latest_at(&app_state.db, window_start)
+            .await?
+            .into_iter()
+            .map(|snapshot| (snapshot.user_id, snapshot.net_worth))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    let mut history_by_user = BTreeMap::<String, Vec<f64>>::new();
+    for snapshot in NetWorthSnapshot::list_since(&app_state.db, window_start).await? {
+        history_by_user
+            .entry(snapshot.user_id)
+            .or_default()
+            .push(snapshot.net_worth);
+    }
 
     let mut context = tera::Context::new();
 
     let mut leaderboard_entries = vec![];
 
     for user in users {
+        let expected_money = expected_money_by_user[&user.id];
+
+        let mut history = history_by_user.remove(&user.id).unwrap_or_default();
+        history.push(expected_money);
+
+        // If there was no snapshot before the window started, measure from the first one inside it
+        let baseline = baselines.get(&user.id).copied().unwrap_or(history[0]);
+
         leaderboard_entries.push(LeaderboardEntry {
+            max_money: holdings.max_money(&user),
+            user_id: user.id.clone(),
             name: user.name,
             liquid_money: user.money,
-            expected_money: user.money
-                + bets
-                    .iter()
-                    .map(|bet| {
-                        let probability_of_yes = bet.no_pool / (bet.yes_pool + bet.no_pool);
-
-                        let possible_starting_pool_money = if bet.creator_id == user.id {
-                            (bet.yes_pool * probability_of_yes)
-                                + (bet.no_pool * (1.0 - probability_of_yes))
-                        } else {
-                            0.0
-                        };
-
-                        let yes_bet_money = user_bets
-                            .iter()
-                            .find(|user_bet| {
-                                user_bet.is_yes
-                                    && user_bet.user_id == user.id
-                                    && user_bet.bet_id == bet.id
-                            })
-                            .map(|yes_bet| yes_bet.amount as f64 * probability_of_yes)
-                            .unwrap_or(0.0);
+            expected_money,
+            expected_money_change: expected_money - baseline,
+            sparkline: sparkline_points(&history),
+        })
+    }
 
-                        let no_bet_money = user_bets
-                            .iter()
-                            .find(|user_bet| {
-                                !user_bet.is_yes
-                                    && user_bet.user_id == user.id
-                                    && user_bet.bet_id == bet.id
-                            })
-                            .map(|no_bet| no_bet.amount as f64 * (1.0 - probability_of_yes))
-                            .unwrap_or(0.0);
-
-                        yes_bet_money + no_bet_money + possible_starting_pool_money
-                    })
-                    .sum::<f64>(),
-            max_money: user.money
-                + bets
-                    .iter()
-                    .map(|bet| {
-                        let yes_amount = user_bets
+    context.insert("leaderboard_entries", &leaderboard_entries);
+    context.insert("window", &query.window);
+    context.insert("window_options", &LeaderboardWindow::OPTIONS);
+    context.insert(
+        "current_season_name",
+        &current_season.map(|season| season.name),
+    );
+
+    Ok(Html(app_state.engine.render("leaderboard", &context)?))
+}
+
+/// Scales values into a 100x20 box, evenly spaced from left to right
+fn sparkline_points(values: &[f64]) -> String {
+    let step = values.len().div_ceil(MAX_SPARKLINE_POINTS).max(1);
+    let mut sampled = values.iter().copied().step_by(step).collect::<Vec<_>>();
+    // Always end on the current value
+    if !(values.len() - 1).is_multiple_of(step) {
+        sampled.push(values[values.len() - 1]);
+    }
+
+    let min = sampled.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = sampled.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = if max > min { max - min } else { 1.0 };
+    let x_step = if sampled.len() > 1 {
+        100.0 / (sampled.len() - 1) as f64
+    } else {
+        0.0
+    };
+
+    sampled
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            format!(
+                "{:.1},{:.1}",
+                index as f64 * x_step,
+                20.0 - (value - min) / range * 20.0
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Bets and user_bets indexed so that valuing a user only touches the markets they're involved in,
+/// instead of scanning every user_bet for every (user, bet) pair
+pub struct Holdings<'a> {
+    user_bets: HashMap<(&'a str, &'a str, bool), &'a UserBet>,
+    /// Markets each user has bet in or created
+    bets_by_user: HashMap<&'a str, Vec<&'a Bet>>,
+}
+impl<'a> Holdings<'a> {
+    pub fn new(bets: &'a [Bet], user_bets: &'a [UserBet]) -> Self {
+        let bets_by_id = bets
+            .iter()
+            .map(|bet| (bet.id.as_str(), bet))
+            .collect::<HashMap<_, _>>();
+
+        let mut bet_ids_by_user = HashMap::<&str, HashSet<&str>>::new();
+        for bet in bets {
+            bet_ids_by_user
+                .entry(&bet.creator_id)
+                .or_default()
+                .insert(&bet.id);
+        }
+        for user_bet in user_bets {
+            if bets_by_id.contains_key(user_bet.bet_id.as_str()) {
+                bet_ids_by_user
+                    .entry(&user_bet.user_id)
+                    .or_default()
+                    .insert(&user_bet.bet_id);
+            }
+        }
+
+        Self {
+            user_bets: user_bets
+                .iter()
+                .map(|user_bet| {
+                    (
+                        (
+                            user_bet.user_id.as_str(),
+                            user_bet.bet_id.as_str(),
+                            user_bet.is_yes,
+                        ),
+                        user_bet,
+                    )
+                })
+                .collect(),
+            bets_by_user: bet_ids_by_user
+                .into_iter()
+                .map(|(user_id, bet_ids)| {
+                    (
+                        user_id,
+                        bet_ids
+                            .into_iter()
+                            .map(|bet_id| bets_by_id[bet_id])
+                            .collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn user_bet(&self, user: &User, bet: &Bet, is_yes: bool) -> Option<&'a UserBet> {
+        self.user_bets
+            .get(&(user.id.as_str(), bet.id.as_str(), is_yes))
+            .copied()
+    }
+
+    fn bets_for(&self, user: &User) -> &[&'a Bet] {
+        self.bets_by_user
+            .get(user.id.as_str())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Cash plus what every position (and liquidity pool, for creators) would be worth if each
+    /// market resolved according to its current probability
+    pub fn expected_money(&self, user: &User) -> f64 {
+        user.money
+            + self
+                .bets_for(user)
+                .iter()
+                .map(|bet| {
+                    let probability_of_yes = bet.probability_of_yes();
+
+                    let possible_starting_pool_money = if bet.creator_id == user.id {
+                        (bet.yes_pool * probability_of_yes)
+                            + (bet.no_pool * (1.0 - probability_of_yes))
+                    } else {
+                        0.0
+                    };
+
+                    let yes_bet_money = self
+                        .user_bet(user, bet, true)
+                        .map(|yes_bet| yes_bet.amount as f64 * probability_of_yes)
+                        .unwrap_or(0.0);
+
+                    let no_bet_money = self
+                        .user_bet(user, bet, false)
+                        .map(|no_bet| no_bet.amount as f64 * (1.0 - probability_of_yes))
+                        .unwrap_or(0.0);
+
+                    yes_bet_money + no_bet_money + possible_starting_pool_money
+                })
+                .sum::<f64>()
+    }
+
+    /// Cash plus what every position (and liquidity pool, for creators) would be worth if each
+    /// market resolved in the user's favour
+    pub fn max_money(&self, user: &User) -> f64 {
+        user.money
+            + self
+                .bets_for(user)
+                .iter()
+                .map(|bet| {
+                    let held = |is_yes: bool| {
+                        self.user_bet(user, bet, is_yes)
+                            .map(|user_bet| user_bet.amount as f64)
+                            .unwrap_or(0.0)
+                    };
+                    let (yes_pool, no_pool) = if bet.creator_id == user.id {
+                        (bet.yes_pool, bet.no_pool)
+                    } else {
+                        (0.0, 0.0)
+                    };
+                    (held(true) + yes_pool).max(held(false) + no_pool)
+                })
+                .sum::<f64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Small deterministic xorshift generator, so that failures can be reproduced
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn generate(
+        users: usize,
+        bets: usize,
+        user_bets: usize,
+    ) -> (Vec<User>, Vec<Bet>, Vec<UserBet>) {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        let users = (0..users)
+            .map(|index| User {
+                id: format!("user-{index}"),
+                name: format!("User {index}"),
+                money: rng.below(1000) as f64,
+                moderator: false,
+                email: None,
+                email_notifications: false,
+            })
+            .collect::<Vec<_>>();
+
+        let bets = (0..bets)
+            .map(|index| Bet {
+                id: format!("bet-{index}"),
+                creator_id: users[rng.below(users.len())].id.clone(),
+                created_seconds_since_epoch: index,
+                name: format!("Bet {index}"),
+                description: String::new(),
+                resolution_criteria: String::new(),
+                tags: vec![],
+                closes_at: None,
+                closed: false,
+                yes_pool: 1.0 + rng.below(200) as f64,
+                no_pool: 1.0 + rng.below(200) as f64,
+                liquidity: 1.0 + rng.below(200) as f64,
+            })
+            .collect::<Vec<_>>();
+
+        let mut seen = HashSet::new();
+        let mut generated_user_bets = vec![];
+        while generated_user_bets.len() < user_bets {
+            let user_bet = UserBet {
+                user_id: users[rng.below(users.len())].id.clone(),
+                bet_id: bets[rng.below(bets.len())].id.clone(),
+                is_yes: rng.below(2) == 0,
+                amount: 1 + rng.below(50),
+                spent: rng.below(50) as f64,
+            };
+            if seen.insert((
+                user_bet.user_id.clone(),
+                user_bet.bet_id.clone(),
+                user_bet.is_yes,
+            )) {
+                generated_user_bets.push(user_bet);
+            }
+        }
+
+        (users, bets, generated_user_bets)
+    }
+
+    /// How the leaderboard used to value users, scanning every user_bet for every (user, bet) pair
+    fn naive_expected_money(user: &User, bets: &[Bet], user_bets: &[UserBet]) -> f64 {
+        user.money
+            + bets
+                .iter()
+                .map(|bet| {
+                    let probability_of_yes = bet.probability_of_yes();
+                    let pool = if bet.creator_id == user.id {
+                        (bet.yes_pool * probability_of_yes)
+                            + (bet.no_pool * (1.0 - probability_of_yes))
+                    } else {
+                        0.0
+                    };
+                    let held = |is_yes: bool| {
+                        user_bets
                             .iter()
                             .find(|user_bet| {
-                                user_bet.is_yes
+                                user_bet.is_yes == is_yes
                                     && user_bet.user_id == user.id
                                     && user_bet.bet_id == bet.id
                             })
-                            .map(|yes_bet| {
-                                yes_bet.amount as f64
-                                    + if bet.creator_id == user.id {
-                                        bet.yes_pool
-                                    } else {
-                                        0.0
-                                    }
-                            })
-                            .unwrap_or(0.0);
+                            .map(|user_bet| user_bet.amount as f64)
+                            .unwrap_or(0.0)
+                    };
+                    held(true) * probability_of_yes
+                        + held(false) * (1.0 - probability_of_yes)
+                        + pool
+                })
+                .sum::<f64>()
+    }
 
-                        let no_amount = user_bets
+    fn naive_max_money(user: &User, bets: &[Bet], user_bets: &[UserBet]) -> f64 {
+        user.money
+            + bets
+                .iter()
+                .map(|bet| {
+                    let held = |is_yes: bool, pool: f64| {
+                        user_bets
                             .iter()
                             .find(|user_bet| {
-                                !user_bet.is_yes
+                                user_bet.is_yes == is_yes
                                     && user_bet.user_id == user.id
                                     && user_bet.bet_id == bet.id
                             })
-                            .map(|no_bet| {
-                                no_bet.amount as f64
-                                    + if bet.creator_id == user.id {
-                                        bet.no_pool
-                                    } else {
-                                        0.0
-                                    }
-                            })
-                            .unwrap_or(0.0);
-                        yes_amount.max(no_amount)
-                    })
-                    .sum::<f64>(),
-        })
+                            .map(|user_bet| user_bet.amount as f64)
+                            .unwrap_or(0.0)
+                            + if bet.creator_id == user.id { pool } else { 0.0 }
+                    };
+                    held(true, bet.yes_pool).max(held(false, bet.no_pool))
+                })
+                .sum::<f64>()
     }
 
-    context.insert("leaderboard_entries", &leaderboard_entries);
+    #[test]
+    fn holdings_match_naive_valuation() {
+        let (users, bets, user_bets) = generate(30, 40, 400);
+        let holdings = Holdings::new(&bets, &user_bets);
+
+        for user in users.iter() {
+            let expected = naive_expected_money(user, &bets, &user_bets);
+            let max = naive_max_money(user, &bets, &user_bets);
+            assert!((holdings.expected_money(user) - expected).abs() < 1e-9);
+            assert!((holdings.max_money(user) - max).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn holdings_ignore_positions_in_missing_markets() {
+        let (users, bets, user_bets) = generate(5, 10, 60);
+        // As if the last market was resolved between loading bets and user_bets
+        let holdings = Holdings::new(&bets[..9], &user_bets);
+
+        for user in users.iter() {
+            let expected = naive_expected_money(user, &bets[..9], &user_bets);
+            assert!((holdings.expected_money(user) - expected).abs() < 1e-9);
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture leaderboard_benchmark`
+    #[test]
+    #[ignore]
+    fn leaderboard_benchmark() {
+        for (users, bets, user_bets) in [
+            (100, 100, 2_000),
+            (1_000, 1_000, 20_000),
+            (5_000, 5_000, 100_000),
+        ] {
+            let (users, bets, user_bets) = generate(users, bets, user_bets);
+
+            let start = Instant::now();
+            let holdings = Holdings::new(&bets, &user_bets);
+            let total = users
+                .iter()
+                .map(|user| holdings.expected_money(user) + holdings.max_money(user))
+                .sum::<f64>();
+            let indexed = start.elapsed();
 
-    Html(app_state.engine.render("leaderboard", &context).unwrap())
+            // The old approach is far too slow to run for everybody at the larger sizes, so time a
+            // couple of users and extrapolate
+            let sample = 2;
+            let start = Instant::now();
+            for user in users.iter().take(sample) {
+                naive_expected_money(user, &bets, &user_bets);
+                naive_max_money(user, &bets, &user_bets);
+            }
+            let naive = start.elapsed() * (users.len() / sample) as u32;
+
+            println!(
+                "{} users, {} markets, {} user_bets: indexed {:?}, naive ~{:?} (checksum {total:.2})",
+                users.len(),
+                bets.len(),
+                user_bets.len(),
+                indexed,
+                naive,
+            );
+        }
+    }
 }
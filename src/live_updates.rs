@@ -0,0 +1,156 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    response::{
+        sse::{self, KeepAlive},
+        Sse,
+    },
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{BroadcastStream, WatchStream},
+    Stream, StreamExt,
+};
+
+use crate::{
+    error::AppResult,
+    model::{Bet, Event},
+    repository::Repository,
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+/// Something that happened which an open dashboard should reflect without a reload
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum LiveUpdate {
+    BetUpdated(BetSnapshot),
+    BetCreated { bet_id: String, name: String },
+    BetResolved { bet_id: String },
+    Log(LogSnapshot),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BetSnapshot {
+    bet_id: String,
+    yes_pool: f64,
+    no_pool: f64,
+    probability_of_yes: f64,
+    closed: bool,
+}
+impl From<&Bet> for BetSnapshot {
+    fn from(bet: &Bet) -> Self {
+        Self {
+            bet_id: bet.id.clone(),
+            yes_pool: bet.yes_pool,
+            no_pool: bet.no_pool,
+            probability_of_yes: bet.probability_of_yes(),
+            closed: bet.is_closed(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogSnapshot {
+    id: String,
+    created_at_millis: i64,
+    actor_id: Option<String>,
+    bet_id: Option<String>,
+    content: String,
+}
+impl From<&Event> for LogSnapshot {
+    fn from(event: &Event) -> Self {
+        Self {
+            id: event.id.clone(),
+            created_at_millis: event.created_at.timestamp_millis(),
+            actor_id: event.actor_id.clone(),
+            bet_id: event.bet_id.clone(),
+            content: event.describe(),
+        }
+    }
+}
+
+/// In-process fan-out of live updates to every connected event stream. This only reaches clients
+/// connected to the same process, which is why the dashboard polls instead when running on lambda.
+#[derive(Clone)]
+pub struct LiveUpdates {
+    sender: broadcast::Sender<LiveUpdate>,
+}
+impl Default for LiveUpdates {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(100);
+        Self { sender }
+    }
+}
+impl LiveUpdates {
+    pub fn publish(&self, update: LiveUpdate) {
+        // An error here just means that nobody is listening
+        let _ = self.sender.send(update);
+    }
+
+    pub fn publish_event(&self, event: &Event) {
+        self.publish(LiveUpdate::Log(event.into()));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveUpdate> {
+        self.sender.subscribe()
+    }
+}
+
+/// Streams updates until the server starts shutting down, so that shutdown doesn't wait on them. The
+/// browser reconnects by itself
+pub async fn live_updates_stream(
+    ExtractUserId(_user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let updates = BroadcastStream::new(app_state.live_updates.subscribe()).map(|update| {
+        Some(match update {
+            Ok(update) => Ok(sse::Event::default().json_data(update).unwrap()),
+            // The client fell too far behind and missed some updates - tell it to reload
+            Err(_) => Ok(sse::Event::default().event("lagged").data("")),
+        })
+    });
+    let shutdown_started = WatchStream::new(app_state.background.shutting_down())
+        .filter(|started| *started)
+        .map(|_| None);
+    let stream = updates
+        .merge(shutdown_started)
+        .take_while(Option::is_some)
+        .filter_map(|event| event);
+
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+#[derive(Deserialize)]
+pub struct PollUpdatesRequest {
+    /// The `seq` of the newest log the client has
+    #[serde(default)]
+    after: i64,
+}
+#[derive(Serialize)]
+pub struct PollUpdatesResponse {
+    bets: Vec<BetSnapshot>,
+    /// Oldest first. There can be more than these, which the next poll picks up
+    logs: Vec<LogSnapshot>,
+    /// What to poll after next time, which is the same as the request's if there weren't any logs
+    cursor: i64,
+}
+/// Fallback for when long-lived connections aren't possible. Returns the current state of every bet
+/// (so the client can tell which ones were created or resolved) and the logs after the cursor
+pub async fn poll_updates<R: Repository>(
+    ExtractUserId(_user_id): ExtractUserId,
+    State(app_state): State<AppState<R>>,
+    Query(request): Query<PollUpdatesRequest>,
+) -> AppResult<Json<PollUpdatesResponse>> {
+    let bets = app_state.db.list_bets().await?;
+    let events = app_state.db.list_events_after(request.after).await?;
+
+    Ok(Json(PollUpdatesResponse {
+        bets: bets.iter().map(BetSnapshot::from).collect(),
+        logs: events.iter().map(LogSnapshot::from).collect(),
+        cursor: events.last().map_or(request.after, |newest| newest.seq),
+    }))
+}
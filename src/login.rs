@@ -3,25 +3,31 @@ use axum::{
     response::{Html, IntoResponse, Redirect, Response},
     Form,
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
+use axum_extra::extract::CookieJar;
 use serde::Deserialize;
 
-use crate::{jwt::create_jwt, model::User, user_id_cookie::ExtractUserId, AppState};
+use crate::{
+    csrf::CsrfToken,
+    error::AppResult,
+    jwt::create_jwt,
+    model::User,
+    user_id_cookie::{hardened_cookie, ExtractUserId},
+    AppState,
+};
 
 pub async fn login_page(
     possible_user_id_cookie: Option<ExtractUserId>,
+    csrf_token: CsrfToken,
     State(app_state): State<AppState>,
-) -> Response {
-    match possible_user_id_cookie {
+) -> AppResult<Response> {
+    Ok(match possible_user_id_cookie {
         Some(_) => Redirect::to("/").into_response(),
-        None => Html(
-            app_state
-                .engine
-                .render("login", &tera::Context::new())
-                .unwrap(),
-        )
-        .into_response(),
-    }
+        None => {
+            let mut context = tera::Context::new();
+            context.insert("csrf_token", &csrf_token.0);
+            Html(app_state.engine.render("login", &context)?).into_response()
+        }
+    })
 }
 
 #[derive(Deserialize)]
@@ -32,17 +38,19 @@ pub async fn login(
     jar: CookieJar,
     State(app_state): State<AppState>,
     Form(request): Form<LoginForm>,
-) -> Response {
-    match User::get_by_id(&app_state.pool, &request.user_id).await {
-        Some(_) => {
-            let jwt = create_jwt(&request.user_id, &app_state.secret);
+) -> AppResult<Response> {
+    Ok(
+        match User::get_by_id(&app_state.db, &request.user_id).await? {
+            Some(_) => {
+                let jwt = create_jwt(&request.user_id, &app_state.secret);
 
-            let mut cookie = Cookie::new("betting-auth", jwt);
+                let mut cookie = hardened_cookie("betting-auth", jwt, &app_state.site_url);
 
-            cookie.make_permanent();
+                cookie.make_permanent();
 
-            (jar.add(cookie), Redirect::to("/")).into_response()
-        }
-        None => Redirect::to("/login").into_response(),
-    }
+                (jar.add(cookie), Redirect::to("/")).into_response()
+            }
+            None => Redirect::to("/login").into_response(),
+        },
+    )
 }
@@ -1,42 +1,79 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
-    debug_handler,
-    extract::{FromRef, State},
+    extract::{Query, State},
     http::{header, StatusCode},
+    middleware,
     response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
     Form, Router,
 };
-use axum_lambda_util::run_router;
+use axum_lambda_util::{is_running_on_lambda, run_router, ServerConfig};
+use background::Background;
+use chrono::{DateTime, Utc};
+use csrf::CsrfToken;
+use email::{EmailSender, SmtpEmailSender};
+use envconfig::Envconfig;
+use error::{AppError, AppResult};
+use live_updates::{LiveUpdate, LiveUpdates};
 use log_util::init_default_debug_logger;
 use login::login_page;
-use model::{Bet, LogMessage, User, UserBet, YesOrNo, YesOrNoOrNA};
+use market::{BetChange, Effects};
+use model::{
+    Bet, BetSearch, BetSort, Event, EventPayload, Notification, User, UserBet, YesOrNo, YesOrNoOrNA,
+};
+use rate_limit::RateLimiter;
+use repository::{Repository, RepositoryTransaction};
 use secrets::Secrets;
 use serde::{Deserialize, Serialize};
-use sql_util::get_db_connection_pool;
+use sql_util::{get_db_connection_pool, run_migrations, DbConfig};
 use sqlx::{Pool, Postgres};
 use tera::Tera;
 use user_id_cookie::ExtractUserId;
 use uuid::Uuid;
 
+mod activity;
 mod axum_lambda_util;
+mod background;
+mod comments;
+mod csrf;
+mod email;
+mod error;
 mod jwt;
 mod leaderboard;
+mod live_updates;
 mod log_util;
 mod login;
+mod markdown;
+mod market;
+mod market_page;
+#[cfg(test)]
+mod memory_repository;
 mod model;
+mod notifications;
+mod profile;
+mod rate_limit;
+mod repository;
+mod seasons;
 mod secrets;
+mod signing;
+mod slack;
 mod sql_util;
 mod user_id_cookie;
+mod webhooks;
 
 #[derive(Serialize)]
 struct DashboardBetInfo {
     bet_id: String,
     name: String,
+    description_html: String,
+    resolution_criteria_html: String,
+    tags: Vec<String>,
+    closes_at_seconds: Option<i64>,
     creator_id: String,
     creator_name: String,
     created_seconds_since_epoch: usize,
@@ -47,106 +84,148 @@ struct DashboardBetInfo {
     user_no: Option<UserBet>,
     closed: bool,
 }
+/// Empty strings are treated as missing, because that's what HTML forms send for inputs that weren't
+/// filled in
+#[derive(Debug, Deserialize, Serialize)]
+struct DashboardQuery {
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    tag: String,
+    #[serde(default)]
+    sort: BetSort,
+}
 async fn dashboard(
     ExtractUserId(user_id): ExtractUserId,
+    csrf_token: CsrfToken,
     State(app_state): State<AppState>,
-) -> impl IntoResponse {
+    Query(query): Query<DashboardQuery>,
+) -> AppResult<Html<String>> {
     // No need for transactions in this function because it's readonly. Worst thing that happens is that it gets data from before and after a transaction
-    let logs = LogMessage::list(&app_state.pool).await;
-
-    let mut bets = Bet::list(&app_state.pool).await;
-
-    bets.sort_by(|a, b| {
-        a.created_seconds_since_epoch
-            .cmp(&b.created_seconds_since_epoch)
-            .reverse()
-    });
+    let events = Event::list(&app_state.db).await?;
+
+    let non_empty = |value: &String| (!value.trim().is_empty()).then(|| value.trim().to_string());
+    let bets = Bet::search(
+        &app_state.db,
+        &BetSearch {
+            text: non_empty(&query.q),
+            tag: non_empty(&query.tag),
+            sort: query.sort,
+        },
+    )
+    .await?;
 
     let mut context = tera::Context::new();
+    context.insert("csrf_token", &csrf_token.0);
 
-    let users = User::list(&app_state.pool)
-        .await
+    let users = User::list(&app_state.db)
+        .await?
         .into_iter()
         .map(|user| (user.id.clone(), user))
         .collect::<BTreeMap<String, User>>();
+    let Some(user) = users.get(&user_id) else {
+        return Err(AppError::user_not_found());
+    };
 
-    let user_bets = UserBet::list(&app_state.pool).await;
+    // Only the current user's positions are shown
+    let mut user_bets = UserBet::list_by_user_id(&app_state.db, &user_id)
+        .await?
+        .into_iter()
+        .map(|user_bet| ((user_bet.bet_id.clone(), user_bet.is_yes), user_bet))
+        .collect::<HashMap<(String, bool), UserBet>>();
 
     let mut processed_bets = Vec::new();
 
     for bet in bets {
-        let creator = users
+        // The foreign key means this is always there
+        let creator_name = users
             .get(&bet.creator_id)
-            .expect("Bet can't be created by nonexistent user");
+            .map(|creator| creator.name.clone())
+            .unwrap_or_default();
 
         let processed_bet = DashboardBetInfo {
             bet_id: bet.id.clone(),
             name: bet.name.clone(),
+            description_html: markdown::render(&bet.description),
+            resolution_criteria_html: markdown::render(&bet.resolution_criteria),
+            tags: bet.tags.clone(),
+            closes_at_seconds: bet.closes_at.map(|closes_at| closes_at.timestamp()),
             creator_id: bet.creator_id.clone(),
-            creator_name: creator.name.clone(),
+            creator_name,
             created_seconds_since_epoch: bet.created_seconds_since_epoch,
             yes_pool: bet.yes_pool,
             no_pool: bet.no_pool,
-            probability_of_yes: bet.no_pool / (bet.yes_pool + bet.no_pool),
-            user_yes: user_bets
-                .iter()
-                .find(|user_bet| {
-                    user_bet.is_yes && user_bet.bet_id == bet.id && user_bet.user_id == user_id
-                })
-                .cloned(),
-            user_no: user_bets
-                .iter()
-                .find(|user_bet| {
-                    !user_bet.is_yes && user_bet.bet_id == bet.id && user_bet.user_id == user_id
-                })
-                .cloned(),
-            closed: bet.closed,
+            probability_of_yes: bet.probability_of_yes(),
+            user_yes: user_bets.remove(&(bet.id.clone(), true)),
+            user_no: user_bets.remove(&(bet.id.clone(), false)),
+            closed: bet.is_closed(),
         };
 
         processed_bets.push(processed_bet);
     }
 
     context.insert("bets", &processed_bets);
+    context.insert("query", &query);
+    context.insert("sort_options", &BetSort::OPTIONS);
+    context.insert("tags", &Bet::list_tags(&app_state.db).await?);
+    context.insert("known_bet_ids", &Bet::list_ids(&app_state.db).await?);
+    context.insert(
+        "unread_notifications",
+        &Notification::count_unread(&app_state.db, &user_id).await?,
+    );
+    context.insert("user", user);
     context.insert(
-        "user",
-        users
-            .get(&user_id)
-            .expect("Can't be logged in if user doesn't exist"),
+        "logs_cursor",
+        &events.first().map_or(0, |newest| newest.seq),
     );
     context.insert(
         "logs",
-        &logs
-            .into_iter()
-            .map(|log| (log.created_at.timestamp(), log.content))
+        &events
+            .iter()
+            .map(|event| (event.created_at.timestamp(), event.describe(), &event.id))
             .collect::<Vec<_>>(),
     );
 
-    Html(app_state.engine.render("dashboard", &context).unwrap())
-}
-
-fn share_price(amount: usize, which: &YesOrNo, yes_pool: f64, no_pool: f64) -> Result<f64, ()> {
-    // For NO: X^2+(YES+NO-N)*X-N*YES=0
-    // For YES: X^2+(YES+NO-N)*X-N*NO=0
-    let a = 1.0;
-    let b = yes_pool + no_pool - amount as f64;
-    let c = -(amount as f64)
-        * match which {
-            YesOrNo::Yes => no_pool,
-            YesOrNo::No => yes_pool,
-        };
-
-    let sqrt = (b.powi(2) - (4.0 * a * c)).sqrt();
-
-    if sqrt.is_nan() {
-        Err(())
-    } else {
-        let price = (-b + sqrt) / (2.0 * a);
+    // Lambda can't hold a connection open to stream updates, so the page polls for them instead
+    context.insert(
+        "live_mode",
+        if is_running_on_lambda() {
+            "poll"
+        } else {
+            "stream"
+        },
+    );
 
-        // Round upwards to nearest 100 to make it unprofitable to exploit floating point integer bugs
-        let price = (price * 100.0).ceil() / 100.0;
+    Ok(Html(app_state.engine.render("dashboard", &context)?))
+}
 
-        Ok(price)
+/// Saves everything a market operation changed and records it as an event
+async fn persist_effects(
+    tx: &mut impl RepositoryTransaction,
+    actor: &User,
+    effects: Effects,
+) -> sqlx::Result<Event> {
+    match effects.bet_change {
+        BetChange::Created(bet) => tx.insert_bet(bet).await?,
+        BetChange::PoolsChanged { yes_pool, no_pool } => {
+            tx.update_bet_pools(&effects.bet_id, yes_pool, no_pool)
+                .await?
+        }
+        BetChange::Closed => tx.close_bet(&effects.bet_id).await?,
+        BetChange::Deleted => tx.delete_bet(&effects.bet_id).await?,
+    }
+    for (user_id, amount) in effects.money {
+        tx.add_money(&user_id, amount).await?;
+    }
+    for user_bet in effects.user_bets {
+        tx.save_user_bet(user_bet).await?;
     }
+    for payout in effects.payouts.iter() {
+        tx.insert_payout(payout).await?;
+    }
+
+    tx.insert_event(Some(actor), Some(&effects.bet_id), effects.event)
+        .await
 }
 
 #[derive(Debug, Deserialize)]
@@ -154,231 +233,315 @@ struct PlaceBetRequest {
     bet_id: String,
     amount: usize,
     which: YesOrNo,
-    expected_yes_pool: f64,
-    expected_no_pool: f64,
+    /// The most the client is willing to pay for these shares, so that small price movements between
+    /// page load and submission don't cause the trade to be rejected. Empty if whatever the current
+    /// price is will do, like when the page's script didn't run
+    #[serde(default)]
+    max_cost: String,
+}
+/// Buys shares for the user, returning the bet as it is after the trade and how much was spent.
+/// Shared by the form handler and chat commands, so errors are messages meant for the user
+async fn execute_place_bet<R: Repository>(
+    app_state: &AppState<R>,
+    user_id: &str,
+    request: &PlaceBetRequest,
+) -> AppResult<(Bet, f64)> {
+    let max_cost = match request.max_cost.trim() {
+        "" => f64::INFINITY,
+        max_cost => match max_cost.parse::<f64>() {
+            Ok(max_cost) if max_cost >= 0.0 => max_cost,
+            _ => {
+                return Err(AppError::Validation(format!(
+                    "\"{max_cost}\" is not a valid maximum cost"
+                )))
+            }
+        },
+    };
+    let mut tx = app_state.db.begin().await?;
+
+    let Some(user) = tx.get_user_for_update(user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+    let Some(bet) = tx.get_bet_for_update(&request.bet_id).await? else {
+        return Err(AppError::NotFound("No such market".to_string()));
+    };
+    let position = tx
+        .get_user_bet_for_update(user_id, &request.bet_id, request.which.is_yes())
+        .await?;
+
+    let effects = market::buy(
+        &user,
+        &bet,
+        position.as_ref(),
+        request.which,
+        request.amount,
+        max_cost,
+        Utc::now(),
+    )?;
+
+    let probability_before = bet.probability_of_yes();
+    let Some(spent) = effects.money.get(user_id).map(|change| -change) else {
+        return Err(AppError::Internal(format!(
+            "Buying in {} didn't charge {user_id}",
+            bet.id
+        )));
+    };
+    let bet = effects
+        .bet_change
+        .apply(bet)
+        .expect("Trades don't delete markets");
+
+    let event = persist_effects(&mut tx, &user, effects).await?;
+
+    let user_bets = tx.list_user_bets_for_update(&bet.id).await?;
+    let notifications = tx
+        .insert_notifications(&notifications::sharp_move(
+            &bet,
+            probability_before,
+            &user_bets,
+            &user.id,
+        ))
+        .await?;
+
+    tx.commit().await?;
+
+    app_state
+        .live_updates
+        .publish(LiveUpdate::BetUpdated((&bet).into()));
+    app_state.live_updates.publish_event(&event);
+    notifications::send_emails(app_state, notifications);
+
+    Ok((bet, spent))
 }
-#[debug_handler]
-async fn place_bet(
+async fn place_bet<R: Repository>(
     ExtractUserId(user_id): ExtractUserId,
-    State(app_state): State<AppState>,
+    State(app_state): State<AppState<R>>,
     Form(request): Form<PlaceBetRequest>,
-) -> Response {
-    let mut tx = app_state.pool.begin().await.unwrap();
-
-    let user = User::get_for_update_by_id(&mut tx, &user_id).await.unwrap();
-
-    const ERROR_MARGIN: f64 = 0.0000001;
-    if request.amount > 0 {
-        match Bet::get_for_update_by_id(&mut tx, &request.bet_id).await {
-            Some(mut bet) => {
-                if let Ok(spent) =
-                    share_price(request.amount, &request.which, bet.yes_pool, bet.no_pool)
-                {
-                    if (request.expected_no_pool - bet.no_pool).abs() < ERROR_MARGIN
-                        && (request.expected_yes_pool - bet.yes_pool).abs() < ERROR_MARGIN
-                    {
-                        if user.money >= spent {
-                            if bet.closed {
-                                StatusCode::BAD_REQUEST.into_response()
-                            } else {
-                                let is_yes = request.which.is_yes();
-                                let mut user_bet = UserBet::get_for_update(
-                                    &mut tx,
-                                    &user_id,
-                                    &request.bet_id,
-                                    is_yes,
-                                )
-                                .await
-                                .unwrap_or(UserBet {
-                                    user_id: user_id.clone(),
-                                    bet_id: request.bet_id.clone(),
-                                    is_yes,
-                                    amount: 0,
-                                    spent: 0.0,
-                                });
-
-                                user_bet.amount += request.amount;
-                                user_bet.spent += spent;
-
-                                match request.which {
-                                    YesOrNo::Yes => {
-                                        bet.yes_pool -= request.amount as f64;
-                                    }
-                                    YesOrNo::No => {
-                                        bet.no_pool -= request.amount as f64;
-                                    }
-                                };
-                                bet.yes_pool += spent;
-                                bet.no_pool += spent;
-
-                                // All of the DB updates here
-                                User::add_money(&mut tx, &user_id, -spent).await;
-                                user_bet.update_or_insert(&mut tx).await;
-                                Bet::update_pools(
-                                    &mut tx,
-                                    &request.bet_id,
-                                    bet.yes_pool,
-                                    bet.no_pool,
-                                )
-                                .await;
-
-                                LogMessage::insert(
-                                    &app_state.pool,
-                                    &format!(
-                                        "{} bought {} {} shares in \"{}\" for ${}",
-                                        user.name, request.amount, request.which, bet.name, spent
-                                    ),
-                                )
-                                .await;
-
-                                tx.commit().await.unwrap();
-
-                                Redirect::to("/").into_response()
-                            }
-                        } else {
-                            (StatusCode::BAD_REQUEST, "Not enough money").into_response()
-                        }
-                    } else {
-                        (
-                            StatusCode::CONFLICT,
-                            "Price changed while this request was in flight (Reload the page and try again)",
-                        )
-                            .into_response()
-                    }
-                } else {
-                    (
-                        StatusCode::BAD_REQUEST,
-                        "Bet was too big for such a small starting pool",
-                    )
-                        .into_response()
-                }
-            }
-            None => StatusCode::NOT_FOUND.into_response(),
-        }
-    } else {
-        (StatusCode::BAD_REQUEST, "Can't buy 0 shares").into_response()
-    }
+) -> AppResult<Redirect> {
+    execute_place_bet(&app_state, &user_id, &request).await?;
+    Ok(Redirect::to("/"))
 }
 
 #[derive(Deserialize)]
 struct CreateBetRequest {
     name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    resolution_criteria: String,
+    /// Comma separated
+    #[serde(default)]
+    tags: String,
+    /// RFC 3339, or empty if the market stays open until its creator closes it
+    #[serde(default)]
+    closes_at: String,
     starting_money: usize,
 }
-async fn create_bet(
-    ExtractUserId(user_id): ExtractUserId,
-    State(app_state): State<AppState>,
-    Form(request): Form<CreateBetRequest>,
-) -> Response {
-    let mut tx = app_state.pool.begin().await.unwrap();
-
-    let bet_id = Uuid::new_v4().to_string();
 
-    let user = User::get_for_update_by_id(&mut tx, &user_id).await.unwrap();
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 32;
+
+/// Lowercases tags and joins up words with dashes, so that "Team X" and "team-x" are the same tag
+fn parse_tags(tags: &str) -> Result<Vec<String>, String> {
+    let mut parsed = Vec::<String>::new();
+    for tag in tags.split(',') {
+        let tag = tag
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join("-")
+            .to_lowercase();
+        if tag.is_empty() || parsed.contains(&tag) {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH
+            || !tag
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "\"{tag}\" isn't a valid tag - tags can be up to {MAX_TAG_LENGTH} letters, numbers, dashes and underscores"
+            ));
+        }
+        parsed.push(tag);
+    }
+    if parsed.len() > MAX_TAGS {
+        return Err(format!("Markets can have at most {MAX_TAGS} tags"));
+    }
+    Ok(parsed)
+}
+/// Creates the market and puts the user's starting money into its pools. Shared by the form handler
+/// and chat commands, so errors are messages meant for the user
+async fn execute_create_bet<R: Repository>(
+    app_state: &AppState<R>,
+    user_id: &str,
+    request: CreateBetRequest,
+) -> AppResult<Bet> {
+    let tags = parse_tags(&request.tags).map_err(AppError::Validation)?;
+    let closes_at = match request.closes_at.trim() {
+        "" => None,
+        closes_at => match DateTime::parse_from_rfc3339(closes_at) {
+            Ok(closes_at) if closes_at > Utc::now() => Some(closes_at.to_utc()),
+            Ok(_) => {
+                return Err(AppError::Validation(
+                    "Closing time must be in the future".to_string(),
+                ))
+            }
+            Err(_) => {
+                return Err(AppError::Validation(format!(
+                    "\"{closes_at}\" is not a valid closing time"
+                )))
+            }
+        },
+    };
+    let mut tx = app_state.db.begin().await?;
 
-    if request.starting_money < 20 {
-        (
-            StatusCode::BAD_REQUEST,
-            "You need to put in at least $20 of starting money",
-        )
-            .into_response()
-    } else if user.money >= request.starting_money as f64 {
-        User::add_money(&mut tx, &user_id, -(request.starting_money as f64)).await;
+    let Some(user) = tx.get_user_for_update(user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
 
-        let now = SystemTime::now();
-        let duration = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-        let created_seconds_since_epoch = duration.as_secs() as usize;
+    let now = SystemTime::now();
+    let duration = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
+    let created_seconds_since_epoch = duration.as_secs() as usize;
 
+    let effects = market::create(
+        &user,
         Bet {
-            id: bet_id.clone(),
-            creator_id: user_id.clone(),
+            id: Uuid::new_v4().to_string(),
+            creator_id: user_id.to_string(),
             name: request.name.clone(),
+            description: request.description.trim().to_string(),
+            resolution_criteria: request.resolution_criteria.trim().to_string(),
+            tags,
+            closes_at,
             created_seconds_since_epoch,
             closed: false,
-            yes_pool: request.starting_money as f64,
-            no_pool: request.starting_money as f64,
-        }
-        .insert(&mut tx)
-        .await;
+            yes_pool: 0.0,
+            no_pool: 0.0,
+            liquidity: 0.0,
+        },
+        request.starting_money,
+    )?;
+    let BetChange::Created(bet) = effects.bet_change.clone() else {
+        unreachable!("Creating a market always creates a bet")
+    };
+
+    let event = persist_effects(&mut tx, &user, effects).await?;
+
+    tx.commit().await?;
+
+    app_state.live_updates.publish(LiveUpdate::BetCreated {
+        bet_id: bet.id.clone(),
+        name: request.name,
+    });
+    app_state.live_updates.publish_event(&event);
 
-        // When a user starts a bet, they use the money to buy equal amounts of yes shares and no shares
-        // (price of yes share + price of no share = 1)
-        // Those shares are not "owned" by the creator, but are instead used to provide liquidity
-        UserBet {
-            user_id: user_id.clone(),
-            bet_id: bet_id.clone(),
-            is_yes: true,
-            amount: 0,
-            spent: request.starting_money as f64 / 2.0,
-        }
-        .insert(&mut tx)
-        .await;
-        UserBet {
-            user_id: user_id.clone(),
-            bet_id: bet_id.clone(),
-            is_yes: false,
-            amount: 0,
-            spent: request.starting_money as f64 / 2.0,
-        }
-        .insert(&mut tx)
-        .await;
+    Ok(bet)
+}
+async fn create_bet<R: Repository>(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState<R>>,
+    Form(request): Form<CreateBetRequest>,
+) -> AppResult<Redirect> {
+    execute_create_bet(&app_state, &user_id, request).await?;
+    Ok(Redirect::to("/"))
+}
+
+#[derive(Deserialize)]
+struct EditBetRequest {
+    bet_id: String,
+    description: String,
+    resolution_criteria: String,
+}
+async fn edit_bet<R: Repository>(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState<R>>,
+    Form(request): Form<EditBetRequest>,
+) -> AppResult<Redirect> {
+    let mut tx = app_state.db.begin().await?;
+
+    // Only the creator can edit, and for anybody else it looks like the market doesn't exist
+    let bet = match tx.get_bet_for_update(&request.bet_id).await? {
+        Some(bet) if bet.creator_id == user_id => bet,
+        _ => return Err(AppError::NotFound("No such market".to_string())),
+    };
+    let Some(user) = tx.get_user_for_update(&user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+
+    let description = request.description.trim();
+    let resolution_criteria = request.resolution_criteria.trim();
+    if description == bet.description && resolution_criteria == bet.resolution_criteria {
+        return Ok(Redirect::to(&format!("/bet/{}", bet.id)));
+    }
 
-        LogMessage::insert(
-            &app_state.pool,
-            &format!(
-                "{} created a new market, \"{}\", with a starting pool of {}",
-                user.name, &request.name, request.starting_money
-            ),
+    // Going by positions would miss markets where everybody has since sold out
+    let after_first_trade = tx.has_trades(&bet.id).await?;
+
+    tx.update_bet_details(&bet.id, description, resolution_criteria)
+        .await?;
+
+    let event = tx
+        .insert_event(
+            Some(&user),
+            Some(&bet.id),
+            EventPayload::MarketEdited {
+                bet_name: bet.name.clone(),
+                previous_description: bet.description,
+                previous_resolution_criteria: bet.resolution_criteria,
+                description: description.to_string(),
+                resolution_criteria: resolution_criteria.to_string(),
+                after_first_trade,
+            },
         )
-        .await;
+        .await?;
 
-        tx.commit().await.unwrap();
+    tx.commit().await?;
 
-        Redirect::to("/").into_response()
-    } else {
-        (
-            StatusCode::BAD_REQUEST,
-            "You don't have enough money to create this bet",
-        )
-            .into_response()
-    }
+    app_state.live_updates.publish_event(&event);
+
+    Ok(Redirect::to(&format!("/bet/{}", bet.id)))
 }
 
 #[derive(Deserialize)]
 struct CloseBetRequest {
     bet_id: String,
 }
-async fn close_bet(
+async fn close_bet<R: Repository>(
     ExtractUserId(user_id): ExtractUserId,
-    State(app_state): State<AppState>,
+    State(app_state): State<AppState<R>>,
     Form(request): Form<CloseBetRequest>,
-) -> Response {
-    let mut tx = app_state.pool.begin().await.unwrap();
-
-    let bet = Bet::get_for_update_by_id(&mut tx, &request.bet_id).await;
-
-    match bet {
-        Some(bet) => {
-            if bet.creator_id == user_id {
-                let user = User::get_for_update_by_id(&mut tx, &user_id).await.unwrap();
-
-                Bet::close(&mut tx, &bet.id).await;
-
-                LogMessage::insert(
-                    &app_state.pool,
-                    &format!("{} closed the market \"{}\"", user.name, bet.name),
-                )
-                .await;
-
-                tx.commit().await.unwrap();
-
-                Redirect::to("/").into_response()
-            } else {
-                StatusCode::NOT_FOUND.into_response()
-            }
-        }
-        None => StatusCode::NOT_FOUND.into_response(),
-    }
+) -> AppResult<Redirect> {
+    let mut tx = app_state.db.begin().await?;
+
+    let Some(bet) = tx.get_bet_for_update(&request.bet_id).await? else {
+        return Err(AppError::NotFound("No such market".to_string()));
+    };
+    let Some(user) = tx.get_user_for_update(&user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+
+    let effects = market::close(&user, &bet)?;
+    let bet = effects
+        .bet_change
+        .apply(bet)
+        .expect("Closing doesn't delete markets");
+
+    let event = persist_effects(&mut tx, &user, effects).await?;
+
+    let user_bets = tx.list_user_bets_for_update(&bet.id).await?;
+    let notifications = tx
+        .insert_notifications(&notifications::market_closed(&bet, &user_bets, &user.id))
+        .await?;
+
+    tx.commit().await?;
+
+    app_state
+        .live_updates
+        .publish(LiveUpdate::BetUpdated((&bet).into()));
+    app_state.live_updates.publish_event(&event);
+    notifications::send_emails(&app_state, notifications);
+
+    Ok(Redirect::to("/"))
 }
 
 #[derive(Deserialize)]
@@ -386,133 +549,207 @@ struct ResolveBetRequest {
     bet_id: String,
     which: YesOrNoOrNA,
 }
-async fn resolve_bet(
+async fn resolve_bet<R: Repository>(
     ExtractUserId(user_id): ExtractUserId,
-    State(app_state): State<AppState>,
+    State(app_state): State<AppState<R>>,
     Form(request): Form<ResolveBetRequest>,
-) -> Response {
-    let mut tx = app_state.pool.begin().await.unwrap();
-
-    let bet = Bet::get_for_update_by_id(&mut tx, &request.bet_id).await;
-
-    match bet {
-        Some(bet) => {
-            if bet.creator_id == user_id {
-                let user = User::get_for_update_by_id(&mut tx, &user_id).await.unwrap();
-                let user_bets = UserBet::get_for_update_by_bet_id(&mut tx, &request.bet_id).await;
-
-                Bet::delete(&mut tx, &request.bet_id).await;
-
-                match request.which {
-                    YesOrNoOrNA::Yes => {
-                        User::add_money(&mut tx, &bet.creator_id, bet.yes_pool).await;
-                        for user_bet in user_bets.iter().filter(|user_bet| user_bet.is_yes) {
-                            User::add_money(&mut tx, &user_bet.user_id, user_bet.amount as f64)
-                                .await;
-                        }
-                    }
-                    YesOrNoOrNA::No => {
-                        User::add_money(&mut tx, &bet.creator_id, bet.no_pool).await;
-                        for user_bet in user_bets.iter().filter(|user_bet| !user_bet.is_yes) {
-                            User::add_money(&mut tx, &user_bet.user_id, user_bet.amount as f64)
-                                .await;
-                        }
-                    }
-                    YesOrNoOrNA::NA => {
-                        for user_bet in user_bets.iter() {
-                            User::add_money(&mut tx, &user_bet.user_id, user_bet.spent).await;
-                        }
-                    }
-                }
-
-                LogMessage::insert(
-                    &app_state.pool,
-                    &format!(
-                        "{} resolved the market \"{}\" with a result of {}",
-                        user.name, bet.name, request.which
-                    ),
-                )
-                .await;
-
-                tx.commit().await.unwrap();
-
-                Redirect::to("/").into_response()
-            } else {
-                StatusCode::NOT_FOUND.into_response()
-            }
-        }
-        None => StatusCode::NOT_FOUND.into_response(),
-    }
+) -> AppResult<Redirect> {
+    let mut tx = app_state.db.begin().await?;
+
+    let Some(bet) = tx.get_bet_for_update(&request.bet_id).await? else {
+        return Err(AppError::NotFound("No such market".to_string()));
+    };
+    let Some(user) = tx.get_user_for_update(&user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+    let user_bets = tx.list_user_bets_for_update(&bet.id).await?;
+
+    let effects = market::resolve(&user, &bet, &user_bets, request.which)?;
+
+    let notifications = tx
+        .insert_notifications(&notifications::market_resolved(
+            &bet,
+            request.which,
+            &user_bets,
+            &effects.money,
+            &user.id,
+        ))
+        .await?;
+    let event = persist_effects(&mut tx, &user, effects).await?;
+
+    tx.commit().await?;
+
+    app_state
+        .live_updates
+        .publish(LiveUpdate::BetResolved { bet_id: bet.id });
+    app_state.live_updates.publish_event(&event);
+    notifications::send_emails(&app_state, notifications);
+
+    Ok(Redirect::to("/"))
 }
 
-async fn give_money(ExtractUserId(user_id): ExtractUserId, State(app_state): State<AppState>) {
-    let mut tx = app_state.pool.begin().await.unwrap();
-
-    let users = User::list_for_update(&mut tx).await;
-    if user_id
-        == users
-            .iter()
-            .find(|user| user.name == "Jefferson")
-            .unwrap()
-            .id
-    {
-        for user in users {
-            User::add_money(&mut tx, &user.id, 100.0).await
+async fn give_money<R: Repository>(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState<R>>,
+) -> AppResult<()> {
+    let mut tx = app_state.db.begin().await?;
+
+    let users = tx.list_users_for_update().await?;
+    let Some(admin) = users.iter().find(|user| user.is_admin()) else {
+        return Err(AppError::Internal("There's no admin user".to_string()));
+    };
+    if user_id == admin.id {
+        for user in users.iter() {
+            tx.add_money(&user.id, 100.0).await?;
         }
 
-        tx.commit().await.unwrap();
+        let event = tx
+            .insert_event(
+                Some(admin),
+                None,
+                EventPayload::MoneyGranted { amount: 100.0 },
+            )
+            .await?;
 
-        LogMessage::insert(
-            &app_state.pool,
-            "$100 has been added to everybody's account",
-        )
-        .await;
+        tx.commit().await?;
+
+        app_state.live_updates.publish_event(&event);
     }
+
+    Ok(())
 }
 
 pub async fn changelog(
     ExtractUserId(_user_id): ExtractUserId,
     State(app_state): State<AppState>,
-) -> impl IntoResponse {
-    Html(
+) -> AppResult<Html<String>> {
+    Ok(Html(
         app_state
             .engine
-            .render("changelog", &tera::Context::new())
-            .unwrap(),
-    )
+            .render("changelog", &tera::Context::new())?,
+    ))
 }
 
 pub async fn about(
     ExtractUserId(_user_id): ExtractUserId,
     State(app_state): State<AppState>,
-) -> impl IntoResponse {
-    Html(
-        app_state
-            .engine
-            .render("about", &tera::Context::new())
-            .unwrap(),
-    )
+) -> AppResult<Html<String>> {
+    Ok(Html(
+        app_state.engine.render("about", &tera::Context::new())?,
+    ))
+}
+
+/// For load balancers and container orchestrators. Doesn't need a login, and is only OK if the
+/// database can be reached
+async fn healthz(State(app_state): State<AppState>) -> Response {
+    match sql_util::ping(&app_state.db).await {
+        Ok(()) => "OK".into_response(),
+        Err(error) => {
+            log::warn!("Health check couldn't reach the database: {error}");
+            (StatusCode::SERVICE_UNAVAILABLE, "Database unavailable").into_response()
+        }
+    }
 }
 
 type AppEngine = Tera;
 
-// Define your application shared state
-#[derive(Clone, FromRef)]
-pub struct AppState {
+// Define your application shared state. Generic over storage so that the trading routes can be tested
+// without a database, but everything else only works with Postgres
+#[derive(Clone)]
+pub struct AppState<R: Repository = Pool<Postgres>> {
     engine: AppEngine,
     secret: String,
-    pool: Pool<Postgres>,
+    db: R,
+    live_updates: LiveUpdates,
+    /// None when email isn't configured, in which case notifications only go to the inbox
+    email_sender: Option<Arc<dyn EmailSender>>,
+    /// None when chat commands aren't set up
+    slack_signing_secret: Option<String>,
+    /// For links that leave the site, like in emails and chat messages
+    site_url: String,
+    /// Also used directly by chat commands, which don't go through the rate limiting middleware
+    rate_limiter: Arc<RateLimiter>,
+    background: Background,
+}
+
+/// Everything that moves money around. These work with any storage
+fn trading_routes<R: Repository>() -> Router<AppState<R>> {
+    Router::new()
+        .route("/place", post(place_bet))
+        .route("/create", post(create_bet))
+        .route("/edit", post(edit_bet))
+        .route("/close", post(close_bet))
+        .route("/resolve", post(resolve_bet))
+        .route("/give_money", post(give_money))
 }
 
 #[tokio::main]
 async fn main() {
     init_default_debug_logger();
 
-    let env = Secrets::load().await;
+    let env = match Secrets::load().await {
+        Ok(env) => env,
+        Err(error) => {
+            log::error!("Couldn't load secrets: {error}");
+            std::process::exit(1);
+        }
+    };
 
-    let pool = get_db_connection_pool(&env.db_username, &env.db_password)
-        .await
-        .unwrap();
+    let server_config = match ServerConfig::init_from_env() {
+        Ok(server_config) => server_config,
+        Err(error) => {
+            log::error!("Couldn't read the server settings: {error}");
+            std::process::exit(1);
+        }
+    };
+    let db_config = match DbConfig::init_from_env() {
+        Ok(db_config) => db_config,
+        Err(error) => {
+            log::error!("Couldn't read the database settings: {error}");
+            std::process::exit(1);
+        }
+    };
+    let pool = match get_db_connection_pool(
+        &db_config,
+        env.db_username.as_deref(),
+        env.db_password.as_deref(),
+    )
+    .await
+    {
+        Ok(pool) => pool,
+        Err(error) => {
+            log::error!("Couldn't connect to the database: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    // `betting migrate` just migrates the database and exits
+    let migrate_only = std::env::args().nth(1).as_deref() == Some("migrate");
+    if migrate_only || db_config.should_run_migrations() {
+        if let Err(error) = run_migrations(&pool).await {
+            log::error!("Couldn't migrate the database: {error}");
+            std::process::exit(1);
+        }
+    }
+    if migrate_only {
+        log::info!("Database is up to date");
+        return;
+    }
+
+    let site_url = match server_config.site_origin() {
+        Ok(site_url) => site_url.to_string(),
+        Err(error) => {
+            log::error!("{error}");
+            std::process::exit(1);
+        }
+    };
+    let cors_policy = match server_config.cors_policy() {
+        Ok(cors_policy) => cors_policy,
+        Err(error) => {
+            log::error!("Couldn't set up CORS: {error}");
+            std::process::exit(1);
+        }
+    };
 
     // Set up the Handlebars engine with the same route paths as the Axum router
     let mut hbs = Tera::default();
@@ -523,20 +760,84 @@ async fn main() {
         ("leaderboard", include_str!("../data/leaderboard.tera")),
         ("changelog", include_str!("../data/changelog.tera")),
         ("about", include_str!("../data/about.tera")),
+        ("activity", include_str!("../data/activity.tera")),
+        ("profile", include_str!("../data/profile.tera")),
+        ("seasons", include_str!("../data/seasons.tera")),
+        ("season", include_str!("../data/season.tera")),
+        ("market", include_str!("../data/market.tera")),
+        ("notifications", include_str!("../data/notifications.tera")),
+        ("webhooks", include_str!("../data/webhooks.tera")),
+        ("slack_link", include_str!("../data/slack_link.tera")),
     ])
     .unwrap();
 
+    let live_updates = LiveUpdates::default();
+    let background = Background::default();
+    webhooks::spawn_dispatcher(pool.clone(), &live_updates, &background);
+
+    let rate_limiter = match RateLimiter::from_env(&pool, &env.auth_secret) {
+        Ok(rate_limiter) => Arc::new(rate_limiter),
+        Err(error) => {
+            log::error!("Couldn't set up rate limiting: {error}");
+            std::process::exit(1);
+        }
+    };
+    let email_sender = match SmtpEmailSender::from_env(env.smtp_password) {
+        Ok(email_sender) => email_sender,
+        Err(error) => {
+            log::error!("Couldn't set up email: {error}");
+            std::process::exit(1);
+        }
+    };
+    let app_state = AppState {
+        engine: hbs,
+        secret: env.auth_secret,
+        db: pool.clone(),
+        live_updates,
+        email_sender,
+        slack_signing_secret: env.slack_signing_secret,
+        site_url,
+        rate_limiter: rate_limiter.clone(),
+        background: background.clone(),
+    };
+    notifications::spawn_closing_soon_checker(app_state.clone());
     let app = Router::new()
         .route("/", get(dashboard))
         .route("/leaderboard", get(leaderboard::leaderboard))
+        .route("/activity", get(activity::activity))
+        .route("/api/activity", get(activity::activity_api))
+        .route("/bet/:bet_id", get(market_page::market_page))
+        .route("/user/:user_id", get(profile::user_profile))
+        .route("/seasons", get(seasons::seasons))
+        .route("/seasons/:season_id", get(seasons::season_standings))
+        .route("/notifications", get(notifications::notifications_page))
+        .route(
+            "/notifications/read",
+            post(notifications::mark_notifications_read),
+        )
+        .route(
+            "/notifications/settings",
+            post(notifications::update_notification_settings),
+        )
         .route("/changelog", get(changelog))
         .route("/about", get(about))
         .route("/login", get(login_page).post(login::login))
-        .route("/place", post(place_bet))
-        .route("/create", post(create_bet))
-        .route("/close", post(close_bet))
-        .route("/resolve", post(resolve_bet))
-        .route("/give_money", post(give_money))
+        .route("/comment", post(comments::post_comment))
+        .route("/comment/edit", post(comments::edit_comment))
+        .route("/comment/delete", post(comments::delete_comment))
+        .route("/comment/hide", post(comments::hide_comment))
+        .route("/start_season", post(seasons::start_season))
+        .route(
+            "/admin/webhooks",
+            get(webhooks::webhooks_page).post(webhooks::create_webhook),
+        )
+        .route("/admin/webhooks/delete", post(webhooks::delete_webhook))
+        .route("/slack/commands", post(slack::slash_command))
+        .route("/slack/link", get(slack::link_page).post(slack::link))
+        .route("/updates", get(live_updates::poll_updates))
+        .route("/updates/stream", get(live_updates::live_updates_stream))
+        .merge(trading_routes())
+        .route("/healthz", get(healthz))
         .route(
             "/favicon.png",
             get(|| async {
@@ -546,11 +847,879 @@ async fn main() {
                 )
             }),
         )
-        .with_state(AppState {
-            engine: hbs,
-            secret: env.auth_secret,
-            pool,
-        });
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            csrf::protect,
+        ))
+        .layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit::limit,
+        ))
+        .layer(cors_policy)
+        .with_state(app_state);
+
+    run_router(app, &server_config, &background).await;
+    // Every request and background task has finished or been cut off by now
+    pool.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http::{Request, StatusCode};
+    use memory_repository::{MemoryRepository, MemoryState};
+    use proptest::prelude::*;
+    use tower::Service;
+
+    use super::*;
+
+    const SECRET: &str = "test secret";
+    const CSRF_NONCE: &str = "test-nonce";
+
+    struct TestApp {
+        router: Router,
+        db: MemoryRepository,
+    }
+    impl TestApp {
+        /// Starts off with an admin and two other users, each with $1000
+        async fn new() -> Self {
+            let db = MemoryRepository::default();
+            for (id, name) in [("admin", "Jefferson"), ("alice", "Alice"), ("bob", "Bob")] {
+                db.insert_user(User {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                    money: 1000.0,
+                    moderator: false,
+                    email: None,
+                    email_notifications: false,
+                })
+                .await;
+            }
+            let app_state = AppState {
+                engine: Tera::default(),
+                secret: SECRET.to_string(),
+                db: db.clone(),
+                live_updates: LiveUpdates::default(),
+                email_sender: None,
+                slack_signing_secret: None,
+                site_url: "https://betting.example.com".to_string(),
+                rate_limiter: Arc::new(rate_limiter(1000)),
+                background: Background::default(),
+            };
+            let router = trading_routes()
+                .route("/updates", get(live_updates::poll_updates))
+                .layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    csrf::protect,
+                ))
+                .with_state(app_state);
+            Self { router, db }
+        }
+
+        /// Posts the form as `user_id` from one of our pages, returning the response's status
+        async fn post(&mut self, user_id: &str, path: &str, form: &str) -> StatusCode {
+            let form = format!(
+                "{form}&{}={}",
+                csrf::FORM_FIELD,
+                csrf::token_for(SECRET, CSRF_NONCE)
+            );
+            self.post_with_cookie(user_id, path, &form, Some(CSRF_NONCE))
+                .await
+        }
+
+        async fn post_with_cookie(
+            &mut self,
+            user_id: &str,
+            path: &str,
+            form: &str,
+            csrf_nonce: Option<&str>,
+        ) -> StatusCode {
+            let mut cookie = format!("betting-auth={}", jwt::create_jwt(user_id, SECRET));
+            if let Some(csrf_nonce) = csrf_nonce {
+                cookie.push_str(&format!("; {}={csrf_nonce}", csrf::COOKIE_NAME));
+            }
+            let request = Request::post(path)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .header(header::COOKIE, cookie)
+                .body(Body::from(form.to_string()))
+                .unwrap();
+            self.router.call(request).await.unwrap().status()
+        }
+
+        /// Gets the JSON at `path` as `user_id`
+        async fn get_json(&mut self, user_id: &str, path: &str) -> serde_json::Value {
+            let request = Request::get(path)
+                .header(
+                    header::COOKIE,
+                    format!("betting-auth={}", jwt::create_jwt(user_id, SECRET)),
+                )
+                .body(Body::empty())
+                .unwrap();
+            let response = self.router.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        /// Creates a market as `user_id` and returns its ID
+        async fn create(&mut self, user_id: &str, starting_money: usize) -> String {
+            let status = self
+                .post(
+                    user_id,
+                    "/create",
+                    &format!("name=Will+it+rain&starting_money={starting_money}"),
+                )
+                .await;
+            assert_eq!(status, StatusCode::SEE_OTHER);
+            let state = self.db.snapshot().await;
+            state
+                .bets
+                .values()
+                .max_by_key(|bet| bet.created_seconds_since_epoch)
+                .unwrap()
+                .id
+                .clone()
+        }
+
+        async fn state(&self) -> MemoryState {
+            self.db.snapshot().await
+        }
+
+        /// Sells shares as `user_id` straight through the market rules, since there's no route for
+        /// it yet, returning the status a route would have responded with
+        async fn sell(
+            &self,
+            user_id: &str,
+            bet_id: &str,
+            which: YesOrNo,
+            amount: usize,
+        ) -> StatusCode {
+            let mut tx = self.db.begin().await.unwrap();
+            let user = tx.get_user_for_update(user_id).await.unwrap().unwrap();
+            let bet = tx.get_bet_for_update(bet_id).await.unwrap().unwrap();
+            let position = tx
+                .get_user_bet_for_update(user_id, bet_id, which.is_yes())
+                .await
+                .unwrap();
+            match market::sell(
+                &user,
+                &bet,
+                position.as_ref(),
+                which,
+                amount,
+                0.0,
+                Utc::now(),
+            ) {
+                Ok(effects) => {
+                    persist_effects(&mut tx, &user, effects).await.unwrap();
+                    tx.commit().await.unwrap();
+                    StatusCode::SEE_OTHER
+                }
+                Err(error) => AppError::from(error).into_response().status(),
+            }
+        }
+    }
+
+    /// Allows `trade` trades an hour, and 10 of everything else
+    fn rate_limiter(trade: u32) -> RateLimiter {
+        RateLimiter::new(
+            rate_limit::RateLimitConfig {
+                store: "memory".to_string(),
+                window_seconds: 3600,
+                login: 10,
+                trade,
+                create: 10,
+                trust_forwarded_for: Some(false),
+            },
+            Arc::new(rate_limit::MemoryRateLimitStore::default()),
+            SECRET,
+        )
+    }
+
+    fn money(state: &MemoryState, user_id: &str) -> f64 {
+        state.users[user_id].money
+    }
+
+    /// Money is added and subtracted in floats, so it won't always come out exact
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} isn't close to {expected}"
+        );
+    }
+
+    #[tokio::test]
+    async fn creating_a_market_funds_its_pools() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+
+        let state = app.state().await;
+        assert_eq!(money(&state, "alice"), 900.0);
+        let bet = &state.bets[&bet_id];
+        assert_eq!((bet.yes_pool, bet.no_pool), (100.0, 100.0));
+        assert_eq!(bet.creator_id, "alice");
+        assert_eq!(bet.liquidity, 100.0);
+        assert!(state.user_bets.is_empty());
+        assert_eq!(state.events.last().unwrap().payload.kind(), "MarketCreated");
+    }
+
+    #[tokio::test]
+    async fn buying_shares_moves_money_into_the_pools() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                &format!("bet_id={bet_id}&amount=10&which=Yes&max_cost=100"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+
+        let state = app.state().await;
+        let spent = 1000.0 - money(&state, "bob");
+        assert_close(
+            spent,
+            market::share_price(10, &YesOrNo::Yes, 100.0, 100.0).unwrap(),
+        );
+
+        let bet = &state.bets[&bet_id];
+        assert_close(bet.yes_pool, 100.0 + spent - 10.0);
+        assert_close(bet.no_pool, 100.0 + spent);
+        assert!(bet.probability_of_yes() > 0.5);
+
+        let position = state
+            .user_bets
+            .iter()
+            .find(|user_bet| user_bet.user_id == "bob")
+            .unwrap();
+        assert!(position.is_yes);
+        assert_eq!(position.amount, 10);
+        assert_close(position.spent, spent);
+        assert_eq!(state.events.last().unwrap().payload.kind(), "BetPlaced");
+    }
+
+    #[tokio::test]
+    async fn edits_after_everybody_sells_out_are_still_after_the_first_trade() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+        let edit = |description: &str| {
+            format!("bet_id={bet_id}&description={description}&resolution_criteria=")
+        };
+        fn after_first_trade(state: &MemoryState) -> bool {
+            match &state.events.last().unwrap().payload.0 {
+                EventPayload::MarketEdited {
+                    after_first_trade, ..
+                } => *after_first_trade,
+                other => panic!("Expected the market to have been edited, not {other:?}"),
+            }
+        }
+
+        app.post("alice", "/edit", &edit("Before")).await;
+        assert!(!after_first_trade(&app.state().await));
+
+        app.post(
+            "bob",
+            "/place",
+            &format!("bet_id={bet_id}&amount=10&which=Yes&max_cost=100"),
+        )
+        .await;
+        app.sell("bob", &bet_id, YesOrNo::Yes, 10).await;
+        let status = app.post("alice", "/edit", &edit("After")).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(after_first_trade(&app.state().await));
+    }
+
+    #[tokio::test]
+    async fn trades_without_a_max_cost_go_through_at_the_current_price() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+
+        // What the dashboard sends when its script didn't fill the hidden field in
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                &format!("bet_id={bet_id}&amount=10&which=Yes&max_cost="),
+            )
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                &format!("bet_id={bet_id}&amount=10&which=No"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+
+        let after = app.state().await;
+        assert_eq!(
+            after
+                .user_bets
+                .iter()
+                .filter(|user_bet| user_bet.user_id == "bob")
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_trades_change_nothing() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+        let before = app.state().await;
+
+        // More than bob can afford
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                &format!("bet_id={bet_id}&amount=5000&which=No&max_cost=100000"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        // The price moved past what bob was willing to pay
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                &format!("bet_id={bet_id}&amount=10&which=No&max_cost=1"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                &format!("bet_id={bet_id}&amount=10&which=No&max_cost=lots"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                "bet_id=nonexistent&amount=10&which=No&max_cost=100",
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let after = app.state().await;
+        assert_eq!(money(&after, "bob"), money(&before, "bob"));
+        assert_eq!(after.user_bets.len(), before.user_bets.len());
+        assert_eq!(after.events.len(), before.events.len());
+    }
+
+    #[tokio::test]
+    async fn closed_markets_cant_be_traded() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+
+        // Only the creator can close it
+        let status = app.post("bob", "/close", &format!("bet_id={bet_id}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(!app.state().await.bets[&bet_id].closed);
+
+        let status = app
+            .post("alice", "/close", &format!("bet_id={bet_id}"))
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(app.state().await.bets[&bet_id].closed);
+
+        let status = app
+            .post(
+                "bob",
+                "/place",
+                &format!("bet_id={bet_id}&amount=10&which=Yes&max_cost=100"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn resolving_pays_out_winners_and_the_creator() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+        for (user_id, which) in [("bob", "Yes"), ("admin", "No")] {
+            let status = app
+                .post(
+                    user_id,
+                    "/place",
+                    &format!("bet_id={bet_id}&amount=20&which={which}&max_cost=100"),
+                )
+                .await;
+            assert_eq!(status, StatusCode::SEE_OTHER);
+        }
+        let before = app.state().await;
+        let bet = before.bets[&bet_id].clone();
+
+        let status = app
+            .post("bob", "/resolve", &format!("bet_id={bet_id}&which=Yes"))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let status = app
+            .post("alice", "/resolve", &format!("bet_id={bet_id}&which=Yes"))
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+
+        let after = app.state().await;
+        assert!(!after.bets.contains_key(&bet_id));
+        assert!(after.user_bets.is_empty());
+        assert_close(money(&after, "bob"), money(&before, "bob") + 20.0);
+        assert_close(money(&after, "admin"), money(&before, "admin"));
+        assert_close(
+            money(&after, "alice"),
+            money(&before, "alice") + bet.yes_pool,
+        );
+        assert_eq!(after.payouts.len(), 3);
+
+        // Everybody but the creator holding a position hears about it
+        let mut notified = after
+            .notifications
+            .iter()
+            .filter(|notification| notification.kind == "MarketResolved")
+            .map(|notification| notification.user_id.as_str())
+            .collect::<Vec<_>>();
+        notified.sort();
+        assert_eq!(notified, ["admin", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn resolving_na_refunds_what_was_spent() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+        for (user_id, which) in [("bob", "Yes"), ("admin", "No"), ("bob", "No")] {
+            app.post(
+                user_id,
+                "/place",
+                &format!("bet_id={bet_id}&amount=15&which={which}&max_cost=100"),
+            )
+            .await;
+        }
+
+        let status = app
+            .post("alice", "/resolve", &format!("bet_id={bet_id}&which=NA"))
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+
+        let state = app.state().await;
+        for user_id in ["alice", "bob", "admin"] {
+            assert_close(money(&state, user_id), 1000.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_admin_can_give_money() {
+        let mut app = TestApp::new().await;
+
+        app.post("bob", "/give_money", "").await;
+        assert_eq!(money(&app.state().await, "bob"), 1000.0);
+
+        app.post("admin", "/give_money", "").await;
+        let state = app.state().await;
+        for user_id in ["alice", "bob", "admin"] {
+            assert_eq!(money(&state, user_id), 1100.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn users_that_no_longer_exist_are_unauthorized() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+
+        // Still has a valid cookie, but isn't in the database
+        let status = app
+            .post(
+                "deleted",
+                "/place",
+                &format!("bet_id={bet_id}&amount=10&which=Yes&max_cost=100"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let status = app
+            .post("deleted", "/create", "name=Will+it+rain&starting_money=50")
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn health_check_fails_without_a_database() {
+        // Nothing listens on port 1, so every connection attempt fails
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_secs(1))
+            .connect_lazy("postgres://nobody@127.0.0.1:1/nothing")
+            .unwrap();
+        let mut router = Router::new()
+            .route("/healthz", get(healthz))
+            .with_state(AppState {
+                engine: Tera::default(),
+                secret: SECRET.to_string(),
+                db: pool,
+                live_updates: LiveUpdates::default(),
+                email_sender: None,
+                slack_signing_secret: None,
+                site_url: "https://betting.example.com".to_string(),
+                rate_limiter: Arc::new(rate_limiter(1000)),
+                background: Background::default(),
+            });
+
+        let request = Request::get("/healthz").body(Body::empty()).unwrap();
+        let response = router.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn forms_from_other_sites_are_rejected() {
+        let mut app = TestApp::new().await;
+        let before = app.state().await;
+
+        // No token, a token for somebody else's nonce, and no nonce at all
+        let status = app
+            .post_with_cookie("bob", "/give_money", "", Some(CSRF_NONCE))
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let form = format!(
+            "{}={}",
+            csrf::FORM_FIELD,
+            csrf::token_for(SECRET, "another-nonce")
+        );
+        let status = app
+            .post_with_cookie("admin", "/give_money", &form, Some(CSRF_NONCE))
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let form = format!(
+            "{}={}",
+            csrf::FORM_FIELD,
+            csrf::token_for(SECRET, CSRF_NONCE)
+        );
+        let status = app
+            .post_with_cookie("admin", "/give_money", &form, None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(money(&app.state().await, "admin"), money(&before, "admin"));
+
+        let status = app
+            .post_with_cookie("admin", "/give_money", &form, Some(CSRF_NONCE))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            money(&app.state().await, "admin"),
+            money(&before, "admin") + 100.0
+        );
+    }
+
+    #[tokio::test]
+    async fn only_pages_hand_out_csrf_cookies() {
+        let mut app = TestApp::new().await;
+        let request = Request::get("/updates")
+            .header(
+                header::COOKIE,
+                format!("betting-auth={}", jwt::create_jwt("bob", SECRET)),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = app.router.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+    }
+
+    #[tokio::test]
+    async fn trading_too_fast_is_rate_limited() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+        app.router = app.router.layer(middleware::from_fn_with_state(
+            Arc::new(rate_limiter(2)),
+            rate_limit::limit,
+        ));
+
+        let place = format!("bet_id={bet_id}&amount=1&which=Yes&max_cost=100");
+        for _ in 0..2 {
+            assert_eq!(
+                app.post("bob", "/place", &place).await,
+                StatusCode::SEE_OTHER
+            );
+        }
+        let before = app.state().await;
+        assert_eq!(
+            app.post("bob", "/place", &place).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(money(&app.state().await, "bob"), money(&before, "bob"));
+        // Everybody gets their own limit, and other routes aren't affected
+        assert_eq!(
+            app.post("admin", "/place", &place).await,
+            StatusCode::SEE_OTHER
+        );
+        let status = app
+            .post("bob", "/create", "name=Will+it+snow&starting_money=50")
+            .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn polling_only_returns_new_logs() {
+        let mut app = TestApp::new().await;
+        let bet_id = app.create("alice", 100).await;
+
+        let first = app.get_json("bob", "/updates").await;
+        assert_eq!(first["logs"].as_array().unwrap().len(), 1);
+        let cursor = first["cursor"].as_i64().unwrap();
+
+        // Nothing has happened since, so there's nothing to add
+        let second = app
+            .get_json("bob", &format!("/updates?after={cursor}"))
+            .await;
+        assert!(second["logs"].as_array().unwrap().is_empty());
+        assert_eq!(second["cursor"], cursor);
+        assert_eq!(second["bets"].as_array().unwrap().len(), 1);
+
+        app.post(
+            "bob",
+            "/place",
+            &format!("bet_id={bet_id}&amount=10&which=Yes&max_cost=100"),
+        )
+        .await;
+        let third = app
+            .get_json("bob", &format!("/updates?after={cursor}"))
+            .await;
+        assert_eq!(third["logs"].as_array().unwrap().len(), 1);
+        assert_ne!(third["cursor"], cursor);
+    }
+
+    #[tokio::test]
+    async fn polling_catches_up_on_more_logs_than_fit_in_one_response() {
+        let mut app = TestApp::new().await;
+        for index in 0..105 {
+            let creator = ["admin", "alice", "bob"][index % 3];
+            app.create(creator, market::MIN_STARTING_MONEY).await;
+        }
+
+        let first = app.get_json("bob", "/updates?after=0").await;
+        let logs = first["logs"].as_array().unwrap();
+        assert_eq!(logs.len(), 100);
+        let state = app.state().await;
+        assert_eq!(logs[0]["id"], state.events[0].id.as_str());
+
+        let second = app
+            .get_json("bob", &format!("/updates?after={}", first["cursor"]))
+            .await;
+        let logs = second["logs"].as_array().unwrap();
+        assert_eq!(logs.len(), 5);
+        assert_eq!(logs[4]["id"], state.events[104].id.as_str());
+    }
+
+    #[tokio::test]
+    async fn logged_out_users_are_sent_to_login() {
+        let mut app = TestApp::new().await;
+        let request = Request::post("/give_money")
+            .header("x-csrf-token", csrf::token_for(SECRET, CSRF_NONCE))
+            .header(
+                header::COOKIE,
+                format!("{}={CSRF_NONCE}", csrf::COOKIE_NAME),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = app.router.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()[header::LOCATION], "/login");
+    }
 
-    run_router(app).await;
+    #[derive(Debug, Clone)]
+    enum Action {
+        Create {
+            user: usize,
+            starting_money: usize,
+        },
+        Buy {
+            user: usize,
+            market: usize,
+            yes: bool,
+            amount: usize,
+        },
+        Close {
+            market: usize,
+        },
+        Resolve {
+            market: usize,
+            which: YesOrNoOrNA,
+        },
+        GiveMoney,
+    }
+
+    /// Users to trade as, on top of the admin, alice and bob
+    const EXTRA_USERS: usize = 5;
+
+    fn actions() -> impl Strategy<Value = Vec<Action>> {
+        let user = 0..EXTRA_USERS + 3;
+        let market = 0..4_usize;
+        let action = prop_oneof![
+            2 => (user.clone(), 10..300_usize).prop_map(|(user, starting_money)| Action::Create { user, starting_money }),
+            6 => (user, market.clone(), any::<bool>(), 1..80_usize)
+                .prop_map(|(user, market, yes, amount)| Action::Buy { user, market, yes, amount }),
+            1 => market.clone().prop_map(|market| Action::Close { market }),
+            2 => (
+                market,
+                prop_oneof![
+                    Just(YesOrNoOrNA::Yes),
+                    Just(YesOrNoOrNA::No),
+                    Just(YesOrNoOrNA::NA),
+                ],
+            )
+                .prop_map(|(market, which)| Action::Resolve { market, which }),
+            1 => Just(Action::GiveMoney),
+        ];
+        prop::collection::vec(action, 1..40)
+    }
+
+    /// All the money there is: what users hold plus what every market will pay out, which is the
+    /// same whichever way it resolves
+    fn total_money(state: &MemoryState) -> f64 {
+        let held = state.users.values().map(|user| user.money).sum::<f64>();
+        let in_markets = state
+            .bets
+            .values()
+            .map(|bet| bet.yes_pool + shares(state, &bet.id, true))
+            .sum::<f64>();
+        held + in_markets
+    }
+
+    fn shares(state: &MemoryState, bet_id: &str, is_yes: bool) -> f64 {
+        state
+            .user_bets
+            .iter()
+            .filter(|user_bet| user_bet.bet_id == bet_id && user_bet.is_yes == is_yes)
+            .map(|user_bet| user_bet.amount as f64)
+            .sum()
+    }
+
+    fn check_invariants(state: &MemoryState, expected_total: f64) -> Result<(), TestCaseError> {
+        let total = total_money(state);
+        prop_assert!(
+            (total - expected_total).abs() < 1e-6,
+            "there's {total} but there should be {expected_total}"
+        );
+        for user in state.users.values() {
+            prop_assert!(user.money >= 0.0, "{} has {}", user.id, user.money);
+        }
+        for bet in state.bets.values() {
+            prop_assert!(bet.yes_pool >= 0.0 && bet.no_pool >= 0.0);
+            let yes_value = bet.yes_pool + shares(state, &bet.id, true);
+            let no_value = bet.no_pool + shares(state, &bet.id, false);
+            prop_assert!((yes_value - no_value).abs() < 1e-6);
+        }
+        for user_bet in &state.user_bets {
+            prop_assert!(user_bet.spent >= 0.0);
+        }
+        Ok(())
+    }
+
+    /// Runs the actions through the routes, checking that money is only ever created by the admin
+    /// giving it out and that nothing goes negative
+    async fn run_economy(actions: Vec<Action>) -> Result<(), TestCaseError> {
+        let mut app = TestApp::new().await;
+        for index in 0..EXTRA_USERS {
+            app.db
+                .insert_user(User {
+                    id: format!("user-{index}"),
+                    name: format!("User {index}"),
+                    money: 1000.0,
+                    moderator: false,
+                    email: None,
+                    email_notifications: false,
+                })
+                .await;
+        }
+        let mut user_ids = vec!["admin".to_string(), "alice".to_string(), "bob".to_string()];
+        user_ids.extend((0..EXTRA_USERS).map(|index| format!("user-{index}")));
+        let mut expected_total = total_money(&app.state().await);
+
+        for action in actions {
+            let before = app.state().await;
+            let market = |index: usize| before.bets.values().nth(index % before.bets.len().max(1));
+            let status = match &action {
+                Action::Create {
+                    user,
+                    starting_money,
+                } => {
+                    app.post(
+                        &user_ids[*user],
+                        "/create",
+                        &format!("name=Will+it+rain&starting_money={starting_money}"),
+                    )
+                    .await
+                }
+                Action::Buy {
+                    user,
+                    market: index,
+                    yes,
+                    amount,
+                } => {
+                    let Some(bet) = market(*index) else { continue };
+                    let which = if *yes { "Yes" } else { "No" };
+                    app.post(
+                        &user_ids[*user],
+                        "/place",
+                        &format!(
+                            "bet_id={}&amount={amount}&which={which}&max_cost=100000",
+                            bet.id
+                        ),
+                    )
+                    .await
+                }
+                Action::Close { market: index } => {
+                    let Some(bet) = market(*index) else { continue };
+                    app.post(&bet.creator_id, "/close", &format!("bet_id={}", bet.id))
+                        .await
+                }
+                Action::Resolve {
+                    market: index,
+                    which,
+                } => {
+                    let Some(bet) = market(*index) else { continue };
+                    app.post(
+                        &bet.creator_id,
+                        "/resolve",
+                        &format!("bet_id={}&which={which:?}", bet.id),
+                    )
+                    .await
+                }
+                Action::GiveMoney => {
+                    let status = app.post("admin", "/give_money", "").await;
+                    expected_total += 100.0 * user_ids.len() as f64;
+                    status
+                }
+            };
+            prop_assert_ne!(status, StatusCode::INTERNAL_SERVER_ERROR, "{:?}", action);
+
+            let after = app.state().await;
+            check_invariants(&after, expected_total)?;
+            if let Action::Resolve {
+                which: YesOrNoOrNA::NA,
+                ..
+            } = action
+            {
+                // Everybody gets back exactly what they spent
+                for payout in &after.payouts[before.payouts.len()..] {
+                    prop_assert_eq!(payout.payout, payout.spent, "{}", payout.user_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn trading_never_creates_or_destroys_money(actions in actions()) {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(run_economy(actions))?;
+        }
+    }
 }
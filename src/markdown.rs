@@ -0,0 +1,40 @@
+use pulldown_cmark::{html, Options, Parser};
+
+/// Renders user-written markdown to HTML that's safe to put straight into a page. Raw HTML in the
+/// markdown is sanitized rather than escaped, so harmless tags still work
+pub fn render(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+
+    ammonia::Builder::default()
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_markdown() {
+        assert_eq!(
+            render("**Yes** if [this](https://example.com) happens"),
+            "<p><strong>Yes</strong> if <a href=\"https://example.com\" rel=\"noopener noreferrer nofollow\">this</a> happens</p>\n"
+        );
+    }
+
+    #[test]
+    fn strips_scripts_and_event_handlers() {
+        let html = render(
+            "Hi <script>alert(1)</script><img src=x onerror=alert(1)> [link](javascript:alert(1))",
+        );
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("onerror"));
+        assert!(!html.contains("href"));
+    }
+}
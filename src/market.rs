@@ -0,0 +1,888 @@
+//! The rules of the market, kept apart from storage and HTTP. Each operation takes the state it needs,
+//! checks that the trade is allowed, and returns everything that should change as a result. Nothing
+//! here touches the database, so callers load the state (locking it), call one of these, and then
+//! persist the effects in the same transaction.
+//!
+//! Markets are constant product market makers: the creator's starting money buys equal yes and no
+//! pools, and every trade keeps `yes_pool * no_pool` the same.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use chrono::{DateTime, Utc};
+
+use crate::model::{Bet, EventPayload, User, UserBet, YesOrNo, YesOrNoOrNA};
+
+/// Markets need at least this much starting money, because tiny pools let small trades swing the
+/// price wildly
+pub const MIN_STARTING_MONEY: usize = 20;
+
+/// Leeway when comparing a price to the most somebody said they'd pay, so that rounding in the
+/// browser doesn't reject trades
+const ERROR_MARGIN: f64 = 0.0000001;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TradeError {
+    NoShares,
+    /// The trade would drain the pool
+    TooBigForPool,
+    /// The price moved past the limit the trader set
+    PriceChanged {
+        amount: usize,
+        price: f64,
+        limit: f64,
+        buying: bool,
+    },
+    NotEnoughMoney,
+    NotEnoughShares,
+    MarketClosed,
+    StartingMoneyTooLow,
+    CantAffordStartingMoney,
+    /// Only the creator can close or resolve a market
+    NotCreator,
+}
+impl Display for TradeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TradeError::NoShares => f.write_str("Can't trade 0 shares"),
+            TradeError::TooBigForPool => {
+                f.write_str("Bet was too big for such a small starting pool")
+            }
+            TradeError::PriceChanged {
+                amount,
+                price,
+                limit,
+                buying: true,
+            } => write!(
+                f,
+                "Price changed while this request was in flight - {amount} shares now cost ${price}, which is more than your maximum of ${limit} (Reload the page and try again)"
+            ),
+            TradeError::PriceChanged {
+                amount,
+                price,
+                limit,
+                buying: false,
+            } => write!(
+                f,
+                "Price changed while this request was in flight - {amount} shares now sell for ${price}, which is less than your minimum of ${limit} (Reload the page and try again)"
+            ),
+            TradeError::NotEnoughMoney => f.write_str("Not enough money"),
+            TradeError::NotEnoughShares => f.write_str("You don't have that many shares to sell"),
+            TradeError::MarketClosed => f.write_str("This market is closed to trading"),
+            TradeError::StartingMoneyTooLow => write!(
+                f,
+                "You need to put in at least ${MIN_STARTING_MONEY} of starting money"
+            ),
+            TradeError::CantAffordStartingMoney => {
+                f.write_str("You don't have enough money to create this bet")
+            }
+            TradeError::NotCreator => f.write_str("Only the market's creator can do that"),
+        }
+    }
+}
+
+/// What happens to the market itself
+#[derive(Debug, Clone)]
+pub enum BetChange {
+    Created(Bet),
+    PoolsChanged {
+        yes_pool: f64,
+        no_pool: f64,
+    },
+    Closed,
+    /// Resolved markets are deleted, along with their positions
+    Deleted,
+}
+impl BetChange {
+    /// The market as it is afterwards, or None if it's gone
+    pub fn apply(&self, bet: Bet) -> Option<Bet> {
+        match self {
+            BetChange::Created(bet) => Some(bet.clone()),
+            BetChange::PoolsChanged { yes_pool, no_pool } => Some(Bet {
+                yes_pool: *yes_pool,
+                no_pool: *no_pool,
+                ..bet
+            }),
+            BetChange::Closed => Some(Bet {
+                closed: true,
+                ..bet
+            }),
+            BetChange::Deleted => None,
+        }
+    }
+}
+
+/// What a user got back when the market resolved, compared to what they'd put in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payout {
+    pub bet_id: String,
+    pub bet_name: String,
+    pub user_id: String,
+    pub result: YesOrNoOrNA,
+    pub spent: f64,
+    pub payout: f64,
+}
+
+/// Everything that changes because of one operation
+#[derive(Debug, Clone)]
+pub struct Effects {
+    pub bet_id: String,
+    pub bet_change: BetChange,
+    /// Added to each user's money, so spending is negative
+    pub money: BTreeMap<String, f64>,
+    /// Positions to save, replacing any existing ones for the same user and side
+    pub user_bets: Vec<UserBet>,
+    pub payouts: Vec<Payout>,
+    pub event: EventPayload,
+}
+
+/// Cost of buying `amount` shares, rounded up to the cent
+pub fn share_price(amount: usize, which: &YesOrNo, yes_pool: f64, no_pool: f64) -> Result<f64, ()> {
+    // For NO: X^2+(YES+NO-N)*X-N*YES=0
+    // For YES: X^2+(YES+NO-N)*X-N*NO=0
+    let a = 1.0;
+    let b = yes_pool + no_pool - amount as f64;
+    let c = -(amount as f64)
+        * match which {
+            YesOrNo::Yes => no_pool,
+            YesOrNo::No => yes_pool,
+        };
+
+    let sqrt = (b.powi(2) - (4.0 * a * c)).sqrt();
+
+    if sqrt.is_nan() {
+        Err(())
+    } else {
+        let price = (-b + sqrt) / (2.0 * a);
+
+        // Round upwards to nearest 100 to make it unprofitable to exploit floating point integer bugs
+        let price = (price * 100.0).ceil() / 100.0;
+
+        Ok(price)
+    }
+}
+
+/// What selling `amount` shares pays, rounded down to the cent. The shares go back into their pool,
+/// and the proceeds come out of both pools
+#[allow(dead_code)] // Selling isn't offered to users yet
+pub fn sale_price(amount: usize, which: &YesOrNo, yes_pool: f64, no_pool: f64) -> f64 {
+    // For YES: X^2-(YES+NO+N)*X+N*NO=0, and the smaller root is the one that leaves both pools positive
+    // For NO: X^2-(YES+NO+N)*X+N*YES=0
+    let b = yes_pool + no_pool + amount as f64;
+    let c = amount as f64
+        * match which {
+            YesOrNo::Yes => no_pool,
+            YesOrNo::No => yes_pool,
+        };
+
+    let price = (b - (b.powi(2) - 4.0 * c).max(0.0).sqrt()) / 2.0;
+
+    // Round down for the same reason buying rounds up
+    (price * 100.0).floor() / 100.0
+}
+
+/// Starts a market, using the creator's starting money to fund both pools
+pub fn create(creator: &User, bet: Bet, starting_money: usize) -> Result<Effects, TradeError> {
+    if starting_money < MIN_STARTING_MONEY {
+        return Err(TradeError::StartingMoneyTooLow);
+    }
+    if creator.money < starting_money as f64 {
+        return Err(TradeError::CantAffordStartingMoney);
+    }
+
+    // When a user starts a bet, they use the money to buy equal amounts of yes shares and no shares
+    // (price of yes share + price of no share = 1)
+    // Those shares are not "owned" by the creator, but are instead used to provide liquidity, so
+    // they're kept on the bet rather than as a position
+    let bet = Bet {
+        creator_id: creator.id.clone(),
+        closed: false,
+        yes_pool: starting_money as f64,
+        no_pool: starting_money as f64,
+        liquidity: starting_money as f64,
+        ..bet
+    };
+
+    Ok(Effects {
+        bet_id: bet.id.clone(),
+        event: EventPayload::MarketCreated {
+            bet_name: bet.name.clone(),
+            starting_money,
+        },
+        bet_change: BetChange::Created(bet),
+        money: BTreeMap::from([(creator.id.clone(), -(starting_money as f64))]),
+        user_bets: vec![],
+        payouts: vec![],
+    })
+}
+
+/// Buys `amount` shares, as long as they cost no more than `max_cost`. `position` is the user's
+/// existing position on that side of the market, if they have one
+pub fn buy(
+    user: &User,
+    bet: &Bet,
+    position: Option<&UserBet>,
+    which: YesOrNo,
+    amount: usize,
+    max_cost: f64,
+    now: DateTime<Utc>,
+) -> Result<Effects, TradeError> {
+    if amount == 0 {
+        return Err(TradeError::NoShares);
+    }
+    let Ok(spent) = share_price(amount, &which, bet.yes_pool, bet.no_pool) else {
+        return Err(TradeError::TooBigForPool);
+    };
+    if spent > max_cost + ERROR_MARGIN {
+        return Err(TradeError::PriceChanged {
+            amount,
+            price: spent,
+            limit: max_cost,
+            buying: true,
+        });
+    }
+    if user.money < spent {
+        return Err(TradeError::NotEnoughMoney);
+    }
+    if bet.is_closed_at(now) {
+        return Err(TradeError::MarketClosed);
+    }
+
+    let mut user_bet = position.cloned().unwrap_or(UserBet {
+        user_id: user.id.clone(),
+        bet_id: bet.id.clone(),
+        is_yes: which.is_yes(),
+        amount: 0,
+        spent: 0.0,
+    });
+    user_bet.amount += amount;
+    user_bet.spent += spent;
+
+    let (mut yes_pool, mut no_pool) = (bet.yes_pool, bet.no_pool);
+    match which {
+        YesOrNo::Yes => yes_pool -= amount as f64,
+        YesOrNo::No => no_pool -= amount as f64,
+    };
+    yes_pool += spent;
+    no_pool += spent;
+
+    Ok(Effects {
+        bet_id: bet.id.clone(),
+        bet_change: BetChange::PoolsChanged { yes_pool, no_pool },
+        money: BTreeMap::from([(user.id.clone(), -spent)]),
+        user_bets: vec![user_bet],
+        payouts: vec![],
+        event: EventPayload::BetPlaced {
+            bet_name: bet.name.clone(),
+            which,
+            amount,
+            spent,
+            probability_of_yes_after: Some(no_pool / (yes_pool + no_pool)),
+        },
+    })
+}
+
+/// Sells `amount` of the user's shares back to the market, as long as they pay at least
+/// `min_proceeds`. The proceeds come off the position's `spent`, so it's always what the user has
+/// put into the position overall - which is what an N/A resolution refunds. Selling at a profit
+/// takes it below zero, and N/A then takes the profit back
+#[allow(dead_code)] // Selling isn't offered to users yet
+pub fn sell(
+    user: &User,
+    bet: &Bet,
+    position: Option<&UserBet>,
+    which: YesOrNo,
+    amount: usize,
+    min_proceeds: f64,
+    now: DateTime<Utc>,
+) -> Result<Effects, TradeError> {
+    if amount == 0 {
+        return Err(TradeError::NoShares);
+    }
+    let Some(position) = position.filter(|position| position.amount >= amount) else {
+        return Err(TradeError::NotEnoughShares);
+    };
+    if bet.is_closed_at(now) {
+        return Err(TradeError::MarketClosed);
+    }
+    let proceeds = sale_price(amount, &which, bet.yes_pool, bet.no_pool);
+    if proceeds < min_proceeds - ERROR_MARGIN {
+        return Err(TradeError::PriceChanged {
+            amount,
+            price: proceeds,
+            limit: min_proceeds,
+            buying: false,
+        });
+    }
+
+    let user_bet = UserBet {
+        amount: position.amount - amount,
+        spent: position.spent - proceeds,
+        ..position.clone()
+    };
+
+    let (mut yes_pool, mut no_pool) = (bet.yes_pool, bet.no_pool);
+    match which {
+        YesOrNo::Yes => yes_pool += amount as f64,
+        YesOrNo::No => no_pool += amount as f64,
+    };
+    yes_pool -= proceeds;
+    no_pool -= proceeds;
+
+    Ok(Effects {
+        bet_id: bet.id.clone(),
+        bet_change: BetChange::PoolsChanged { yes_pool, no_pool },
+        money: BTreeMap::from([(user.id.clone(), proceeds)]),
+        user_bets: vec![user_bet],
+        payouts: vec![],
+        event: EventPayload::SharesSold {
+            bet_name: bet.name.clone(),
+            which,
+            amount,
+            proceeds,
+            probability_of_yes_after: no_pool / (yes_pool + no_pool),
+        },
+    })
+}
+
+/// Stops trading. Only the creator can do this
+pub fn close(user: &User, bet: &Bet) -> Result<Effects, TradeError> {
+    if bet.creator_id != user.id {
+        return Err(TradeError::NotCreator);
+    }
+    if bet.closed {
+        return Err(TradeError::MarketClosed);
+    }
+
+    Ok(Effects {
+        bet_id: bet.id.clone(),
+        bet_change: BetChange::Closed,
+        money: BTreeMap::new(),
+        user_bets: vec![],
+        payouts: vec![],
+        event: EventPayload::MarketClosed {
+            bet_name: bet.name.clone(),
+        },
+    })
+}
+
+/// Resolves the market on behalf of its creator. See [`settle`]
+pub fn resolve(
+    user: &User,
+    bet: &Bet,
+    user_bets: &[UserBet],
+    which: YesOrNoOrNA,
+) -> Result<Effects, TradeError> {
+    if bet.creator_id != user.id {
+        return Err(TradeError::NotCreator);
+    }
+    Ok(settle(bet, user_bets, which))
+}
+
+/// Pays everybody out and deletes the market. Each winning share pays $1, the creator also gets
+/// whatever is left in the winning pool, and N/A refunds what everybody spent, including the
+/// creator's liquidity. `user_bets` must be every position in the market.
+pub fn settle(bet: &Bet, user_bets: &[UserBet], which: YesOrNoOrNA) -> Effects {
+    // The creator also gets whatever is left in the liquidity pool
+    let mut payouts = BTreeMap::<&str, f64>::new();
+    match which {
+        YesOrNoOrNA::Yes => {
+            *payouts.entry(&bet.creator_id).or_default() += bet.yes_pool;
+            for user_bet in user_bets.iter().filter(|user_bet| user_bet.is_yes) {
+                *payouts.entry(&user_bet.user_id).or_default() += user_bet.amount as f64;
+            }
+        }
+        YesOrNoOrNA::No => {
+            *payouts.entry(&bet.creator_id).or_default() += bet.no_pool;
+            for user_bet in user_bets.iter().filter(|user_bet| !user_bet.is_yes) {
+                *payouts.entry(&user_bet.user_id).or_default() += user_bet.amount as f64;
+            }
+        }
+        YesOrNoOrNA::NA => {
+            *payouts.entry(&bet.creator_id).or_default() += bet.liquidity;
+            for user_bet in user_bets.iter() {
+                *payouts.entry(&user_bet.user_id).or_default() += user_bet.spent;
+            }
+        }
+    }
+
+    let mut spent = BTreeMap::from([(bet.creator_id.as_str(), bet.liquidity)]);
+    for user_bet in user_bets.iter() {
+        *spent.entry(&user_bet.user_id).or_default() += user_bet.spent;
+    }
+
+    let payouts = spent
+        .into_iter()
+        .map(|(user_id, spent)| Payout {
+            bet_id: bet.id.clone(),
+            bet_name: bet.name.clone(),
+            user_id: user_id.to_string(),
+            result: which,
+            spent,
+            payout: payouts.get(user_id).copied().unwrap_or(0.0),
+        })
+        .collect::<Vec<_>>();
+
+    Effects {
+        bet_id: bet.id.clone(),
+        bet_change: BetChange::Deleted,
+        money: payouts
+            .iter()
+            .map(|payout| (payout.user_id.clone(), payout.payout))
+            .collect(),
+        user_bets: vec![],
+        payouts,
+        event: EventPayload::MarketResolved {
+            bet_name: bet.name.clone(),
+            result: which,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn user(id: &str, money: f64) -> User {
+        User {
+            id: id.to_string(),
+            name: id.to_string(),
+            money,
+            moderator: false,
+            email: None,
+            email_notifications: false,
+        }
+    }
+
+    fn draft() -> Bet {
+        Bet {
+            id: "bet".to_string(),
+            creator_id: String::new(),
+            created_seconds_since_epoch: 0,
+            name: "Will it rain".to_string(),
+            description: String::new(),
+            resolution_criteria: String::new(),
+            tags: vec![],
+            closes_at: None,
+            closed: false,
+            yes_pool: 0.0,
+            no_pool: 0.0,
+            liquidity: 0.0,
+        }
+    }
+
+    fn created(starting_money: usize) -> (Bet, Vec<UserBet>) {
+        let effects = create(&user("creator", 1000.0), draft(), starting_money).unwrap();
+        (
+            effects.bet_change.apply(draft()).unwrap(),
+            effects.user_bets,
+        )
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} isn't close to {expected}"
+        );
+    }
+
+    #[test]
+    fn creating_funds_both_pools() {
+        let effects = create(&user("creator", 100.0), draft(), 50).unwrap();
+        let bet = effects.bet_change.apply(draft()).unwrap();
+        assert_eq!((bet.yes_pool, bet.no_pool), (50.0, 50.0));
+        assert_eq!(bet.creator_id, "creator");
+        assert_eq!(effects.money["creator"], -50.0);
+        // The liquidity isn't a position
+        assert_eq!(bet.liquidity, 50.0);
+        assert!(effects.user_bets.is_empty());
+
+        assert_eq!(
+            create(&user("creator", 100.0), draft(), 19).unwrap_err(),
+            TradeError::StartingMoneyTooLow
+        );
+        assert_eq!(
+            create(&user("creator", 100.0), draft(), 101).unwrap_err(),
+            TradeError::CantAffordStartingMoney
+        );
+    }
+
+    #[test]
+    fn buying_moves_the_price() {
+        let (bet, _) = created(100);
+        let effects = buy(
+            &user("trader", 100.0),
+            &bet,
+            None,
+            YesOrNo::Yes,
+            10,
+            100.0,
+            Utc::now(),
+        )
+        .unwrap();
+        let after = effects.bet_change.apply(bet.clone()).unwrap();
+
+        assert!(after.probability_of_yes() > bet.probability_of_yes());
+        let spent = -effects.money["trader"];
+        assert_eq!(spent, 5.13);
+        assert_eq!(effects.user_bets[0].amount, 10);
+        assert_eq!(effects.user_bets[0].spent, spent);
+        // Rounding the price up means the pools never lose value
+        assert!(after.yes_pool * after.no_pool >= bet.yes_pool * bet.no_pool);
+    }
+
+    #[test]
+    fn buying_checks_limits() {
+        let (bet, _) = created(100);
+        let trader = user("trader", 100.0);
+        let now = Utc::now();
+
+        assert_eq!(
+            buy(&trader, &bet, None, YesOrNo::No, 0, 100.0, now).unwrap_err(),
+            TradeError::NoShares
+        );
+        assert!(matches!(
+            buy(&trader, &bet, None, YesOrNo::No, 10, 5.0, now).unwrap_err(),
+            TradeError::PriceChanged { buying: true, .. }
+        ));
+        assert_eq!(
+            buy(&trader, &bet, None, YesOrNo::No, 500, 1000.0, now).unwrap_err(),
+            TradeError::NotEnoughMoney
+        );
+
+        let closed = Bet {
+            closes_at: Some(now),
+            ..bet
+        };
+        assert_eq!(
+            buy(&trader, &closed, None, YesOrNo::No, 10, 100.0, now).unwrap_err(),
+            TradeError::MarketClosed
+        );
+    }
+
+    #[test]
+    fn selling_right_after_buying_never_profits() {
+        let (bet, _) = created(100);
+        let trader = user("trader", 100.0);
+        let now = Utc::now();
+
+        let bought = buy(&trader, &bet, None, YesOrNo::No, 30, 100.0, now).unwrap();
+        let position = bought.user_bets[0].clone();
+        let after_buy = bought.bet_change.apply(bet.clone()).unwrap();
+
+        let sold = sell(
+            &trader,
+            &after_buy,
+            Some(&position),
+            YesOrNo::No,
+            30,
+            0.0,
+            now,
+        )
+        .unwrap();
+        let after_sell = sold.bet_change.apply(after_buy).unwrap();
+
+        assert!(sold.money["trader"] <= -bought.money["trader"]);
+        assert_eq!(sold.user_bets[0].amount, 0);
+        // What the round trip cost
+        assert_close(
+            sold.user_bets[0].spent,
+            -bought.money["trader"] - sold.money["trader"],
+        );
+        assert!(sold.user_bets[0].spent >= 0.0);
+        assert!((after_sell.yes_pool - bet.yes_pool).abs() < 0.05);
+        assert!((after_sell.no_pool - bet.no_pool).abs() < 0.05);
+    }
+
+    #[test]
+    fn selling_takes_the_proceeds_off_what_was_spent() {
+        let (bet, _) = created(100);
+        let position = UserBet {
+            user_id: "trader".to_string(),
+            bet_id: bet.id.clone(),
+            is_yes: true,
+            amount: 10,
+            spent: 6.0,
+        };
+        let effects = sell(
+            &user("trader", 0.0),
+            &bet,
+            Some(&position),
+            YesOrNo::Yes,
+            4,
+            0.0,
+            Utc::now(),
+        )
+        .unwrap();
+        assert_eq!(effects.user_bets[0].amount, 6);
+        assert_close(effects.user_bets[0].spent, 6.0 - effects.money["trader"]);
+
+        assert_eq!(
+            sell(
+                &user("trader", 0.0),
+                &bet,
+                Some(&position),
+                YesOrNo::Yes,
+                11,
+                0.0,
+                Utc::now()
+            )
+            .unwrap_err(),
+            TradeError::NotEnoughShares
+        );
+        assert_eq!(
+            sell(
+                &user("trader", 0.0),
+                &bet,
+                None,
+                YesOrNo::Yes,
+                1,
+                0.0,
+                Utc::now()
+            )
+            .unwrap_err(),
+            TradeError::NotEnoughShares
+        );
+    }
+
+    #[test]
+    fn only_the_creator_closes_and_resolves() {
+        let (bet, user_bets) = created(100);
+        assert_eq!(
+            close(&user("trader", 0.0), &bet).unwrap_err(),
+            TradeError::NotCreator
+        );
+        assert_eq!(
+            resolve(&user("trader", 0.0), &bet, &user_bets, YesOrNoOrNA::Yes).unwrap_err(),
+            TradeError::NotCreator
+        );
+
+        let closed = close(&user("creator", 0.0), &bet)
+            .unwrap()
+            .bet_change
+            .apply(bet)
+            .unwrap();
+        assert!(closed.closed);
+        assert_eq!(
+            close(&user("creator", 0.0), &closed).unwrap_err(),
+            TradeError::MarketClosed
+        );
+    }
+
+    #[test]
+    fn settling_pays_winning_shares_and_whats_left_in_the_pool() {
+        let (bet, mut user_bets) = created(100);
+        let bought = buy(
+            &user("trader", 100.0),
+            &bet,
+            None,
+            YesOrNo::Yes,
+            20,
+            100.0,
+            Utc::now(),
+        )
+        .unwrap();
+        let bet = bought.bet_change.apply(bet).unwrap();
+        user_bets.extend(bought.user_bets);
+
+        let yes = settle(&bet, &user_bets, YesOrNoOrNA::Yes);
+        assert_eq!(yes.money["trader"], 20.0);
+        assert_eq!(yes.money["creator"], bet.yes_pool);
+        assert!(bet.yes_pool < 100.0);
+
+        let no = settle(&bet, &user_bets, YesOrNoOrNA::No);
+        assert_eq!(no.money["trader"], 0.0);
+        assert_eq!(no.money["creator"], bet.no_pool);
+
+        let na = settle(&bet, &user_bets, YesOrNoOrNA::NA);
+        assert_eq!(na.money["trader"], user_bets[0].spent);
+        assert_eq!(na.money["creator"], 100.0);
+        assert!(na
+            .payouts
+            .iter()
+            .all(|payout| payout.payout == payout.spent));
+    }
+
+    #[test]
+    fn creators_trading_doesnt_touch_their_liquidity() {
+        let now = Utc::now();
+        let creator = user("creator", 500.0);
+        let (bet, _) = created(100);
+
+        let bought = buy(&creator, &bet, None, YesOrNo::No, 30, 100.0, now).unwrap();
+        let bet = bought.bet_change.apply(bet).unwrap();
+        let position = bought.user_bets[0].clone();
+        assert_eq!(position.spent, -bought.money["creator"]);
+
+        let sold = sell(&creator, &bet, Some(&position), YesOrNo::No, 20, 0.0, now).unwrap();
+        let bet = sold.bet_change.apply(bet).unwrap();
+        assert_eq!(bet.liquidity, 100.0);
+
+        let na = settle(&bet, &sold.user_bets, YesOrNoOrNA::NA);
+        assert_eq!(na.payouts.len(), 1);
+        assert_close(na.payouts[0].spent, 100.0 + sold.user_bets[0].spent);
+    }
+
+    #[test]
+    fn na_after_a_profitable_sale_takes_the_profit_back() {
+        let now = Utc::now();
+        let (mut bet, mut user_bets) = created(100);
+        for (trader, amount) in [("early", 20), ("late", 60)] {
+            let bought = buy(
+                &user(trader, 500.0),
+                &bet,
+                None,
+                YesOrNo::Yes,
+                amount,
+                500.0,
+                now,
+            )
+            .unwrap();
+            bet = bought.bet_change.apply(bet).unwrap();
+            user_bets.extend(bought.user_bets);
+        }
+        let early = user_bets[0].clone();
+        let sold = sell(
+            &user("early", 0.0),
+            &bet,
+            Some(&early),
+            YesOrNo::Yes,
+            20,
+            0.0,
+            now,
+        )
+        .unwrap();
+        assert!(sold.money["early"] > early.spent);
+        bet = sold.bet_change.apply(bet).unwrap();
+        user_bets[0] = sold.user_bets[0].clone();
+        assert!(user_bets[0].spent < 0.0);
+
+        let na = settle(&bet, &user_bets, YesOrNoOrNA::NA);
+        assert_close(na.money["early"], user_bets[0].spent);
+        assert_eq!(na.money["late"], user_bets[1].spent);
+        assert_eq!(na.money["creator"], 100.0);
+    }
+
+    #[derive(Debug, Clone)]
+    struct Trade {
+        trader: usize,
+        which: YesOrNo,
+        amount: usize,
+        selling: bool,
+    }
+
+    fn trades() -> impl Strategy<Value = Vec<Trade>> {
+        prop::collection::vec(
+            (0..4_usize, any::<bool>(), 1..60_usize, any::<bool>()).prop_map(
+                |(trader, yes, amount, selling)| Trade {
+                    trader,
+                    which: if yes { YesOrNo::Yes } else { YesOrNo::No },
+                    amount,
+                    selling,
+                },
+            ),
+            0..40,
+        )
+    }
+
+    /// Runs the trades against a fresh market, skipping any the rules reject, and returns the market,
+    /// every position, and how much money went into the market overall
+    fn run(starting_money: usize, trades: &[Trade]) -> (Bet, Vec<UserBet>, f64) {
+        let now = Utc::now();
+        let (mut bet, mut user_bets) = created(starting_money);
+        let mut traders = (0..4)
+            .map(|index| user(&format!("trader-{index}"), 500.0))
+            .collect::<Vec<_>>();
+        let mut money_in = starting_money as f64;
+
+        for trade in trades {
+            let trader = &traders[trade.trader];
+            let position = user_bets.iter().find(|user_bet| {
+                user_bet.user_id == trader.id && user_bet.is_yes == trade.which.is_yes()
+            });
+            let result = if trade.selling {
+                sell(trader, &bet, position, trade.which, trade.amount, 0.0, now)
+            } else {
+                buy(
+                    trader,
+                    &bet,
+                    position,
+                    trade.which,
+                    trade.amount,
+                    f64::MAX,
+                    now,
+                )
+            };
+            let Ok(effects) = result else {
+                continue;
+            };
+
+            let product_before = bet.yes_pool * bet.no_pool;
+            bet = effects.bet_change.apply(bet).unwrap();
+            assert!(bet.yes_pool >= 0.0 && bet.no_pool >= 0.0);
+            assert!(bet.yes_pool * bet.no_pool >= product_before - 1e-6);
+
+            let change = effects.money[&trader.id];
+            money_in -= change;
+            traders[trade.trader].money += change;
+            assert!(traders[trade.trader].money >= 0.0);
+
+            for user_bet in effects.user_bets {
+                match user_bets.iter_mut().find(|existing| {
+                    existing.user_id == user_bet.user_id && existing.is_yes == user_bet.is_yes
+                }) {
+                    Some(existing) => *existing = user_bet,
+                    None => user_bets.push(user_bet),
+                }
+            }
+        }
+
+        (bet, user_bets, money_in)
+    }
+
+    proptest! {
+        #[test]
+        fn resolving_pays_out_what_went_in(
+            starting_money in 20..500_usize,
+            trades in trades(),
+            which in prop_oneof![
+                Just(YesOrNoOrNA::Yes),
+                Just(YesOrNoOrNA::No),
+                Just(YesOrNoOrNA::NA),
+            ],
+        ) {
+            let (bet, user_bets, money_in) = run(starting_money, &trades);
+
+            let effects = settle(&bet, &user_bets, which);
+            let paid_out = effects.money.values().sum::<f64>();
+            prop_assert!((paid_out - money_in).abs() < 1e-6, "paid {paid_out}, took {money_in}");
+        }
+
+        #[test]
+        fn na_refunds_exactly_what_was_spent(starting_money in 20..500_usize, trades in trades()) {
+            let (bet, user_bets, _) = run(starting_money, &trades);
+
+            let effects = settle(&bet, &user_bets, YesOrNoOrNA::NA);
+            for payout in effects.payouts {
+                prop_assert_eq!(payout.payout, payout.spent);
+            }
+        }
+
+        #[test]
+        fn markets_hold_what_was_spent_on_them(starting_money in 20..500_usize, trades in trades()) {
+            let (bet, user_bets, _) = run(starting_money, &trades);
+
+            let spent = bet.liquidity + user_bets.iter().map(|user_bet| user_bet.spent).sum::<f64>();
+            for (pool, is_yes) in [(bet.yes_pool, true), (bet.no_pool, false)] {
+                let held = pool
+                    + user_bets
+                        .iter()
+                        .filter(|user_bet| user_bet.is_yes == is_yes)
+                        .map(|user_bet| user_bet.amount as f64)
+                        .sum::<f64>();
+                prop_assert!((held - spent).abs() < 1e-6, "holds {held}, {spent} was spent");
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use axum::{
+    extract::{Path, State},
+    response::Html,
+};
+use serde::Serialize;
+
+use crate::{
+    comments,
+    csrf::CsrfToken,
+    error::{AppError, AppResult},
+    markdown,
+    model::{Bet, Event, EventFilter, EventPayload, User},
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+#[derive(Serialize)]
+struct MarketInfo {
+    bet_id: String,
+    name: String,
+    creator_id: String,
+    creator_name: String,
+    created_seconds_since_epoch: usize,
+    closes_at_seconds: Option<i64>,
+    tags: Vec<String>,
+    probability_of_yes: f64,
+    closed: bool,
+    description: String,
+    resolution_criteria: String,
+    description_html: String,
+    resolution_criteria_html: String,
+}
+
+/// An edit made after people had already traded on the old wording
+#[derive(Serialize)]
+struct AuditedEdit {
+    edited_at_seconds: i64,
+    editor_name: String,
+    description_changed: bool,
+    resolution_criteria_changed: bool,
+    previous_description_html: String,
+    previous_resolution_criteria_html: String,
+    description_html: String,
+    resolution_criteria_html: String,
+}
+
+pub async fn market_page(
+    ExtractUserId(user_id): ExtractUserId,
+    csrf_token: CsrfToken,
+    State(app_state): State<AppState>,
+    Path(bet_id): Path<String>,
+) -> AppResult<Html<String>> {
+    let Some(bet) = Bet::get_by_id(&app_state.db, &bet_id).await? else {
+        return Err(AppError::NotFound("No such market".to_string()));
+    };
+    let Some(creator) = User::get_by_id(&app_state.db, &bet.creator_id).await? else {
+        return Err(AppError::Internal(format!(
+            "Market {} was created by nonexistent user {}",
+            bet.id, bet.creator_id
+        )));
+    };
+    let Some(viewer) = User::get_by_id(&app_state.db, &user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+
+    let comments = comments::list_thread(&app_state, &bet.id, &viewer).await?;
+
+    let audited_edits = Event::list_filtered(
+        &app_state.db,
+        &EventFilter {
+            bet_id: Some(bet.id.clone()),
+            kind: Some("MarketEdited".to_string()),
+            ..Default::default()
+        },
+        None,
+        1000,
+    )
+    .await?
+    .into_iter()
+    .filter_map(|event| match event.payload.0 {
+        EventPayload::MarketEdited {
+            previous_description,
+            previous_resolution_criteria,
+            description,
+            resolution_criteria,
+            after_first_trade: true,
+            ..
+        } => Some(AuditedEdit {
+            edited_at_seconds: event.created_at.timestamp(),
+            editor_name: event.actor_name.unwrap_or_default(),
+            description_changed: previous_description != description,
+            resolution_criteria_changed: previous_resolution_criteria != resolution_criteria,
+            previous_description_html: markdown::render(&previous_description),
+            previous_resolution_criteria_html: markdown::render(&previous_resolution_criteria),
+            description_html: markdown::render(&description),
+            resolution_criteria_html: markdown::render(&resolution_criteria),
+        }),
+        _ => None,
+    })
+    .collect::<Vec<_>>();
+
+    let mut context = tera::Context::new();
+    context.insert("csrf_token", &csrf_token.0);
+    context.insert("is_creator", &(bet.creator_id == user_id));
+    context.insert(
+        "market",
+        &MarketInfo {
+            probability_of_yes: bet.probability_of_yes(),
+            closed: bet.is_closed(),
+            closes_at_seconds: bet.closes_at.map(|closes_at| closes_at.timestamp()),
+            description_html: markdown::render(&bet.description),
+            resolution_criteria_html: markdown::render(&bet.resolution_criteria),
+            bet_id: bet.id,
+            name: bet.name,
+            creator_id: bet.creator_id,
+            creator_name: creator.name,
+            created_seconds_since_epoch: bet.created_seconds_since_epoch,
+            tags: bet.tags,
+            description: bet.description,
+            resolution_criteria: bet.resolution_criteria,
+        },
+    );
+    context.insert("audited_edits", &audited_edits);
+    context.insert("comments", &comments);
+
+    Ok(Html(app_state.engine.render("market", &context)?))
+}
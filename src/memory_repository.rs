@@ -0,0 +1,244 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use chrono::{SubsecRound, Utc};
+use sqlx::types::Json;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+use crate::{
+    market,
+    model::{Bet, Event, EventPayload, NewNotification, Notification, User, UserBet},
+    repository::{Repository, RepositoryTransaction},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryState {
+    pub users: BTreeMap<String, User>,
+    pub bets: BTreeMap<String, Bet>,
+    pub user_bets: Vec<UserBet>,
+    pub events: Vec<Event>,
+    pub payouts: Vec<market::Payout>,
+    pub notifications: Vec<Notification>,
+    /// (user_id, dedupe_key) pairs that have already been notified
+    notification_keys: BTreeSet<(String, String)>,
+}
+
+/// Keeps everything in memory, for tests. Transactions lock the whole thing and work on a copy, so
+/// they're fully serialized and dropping one without committing rolls it back
+#[derive(Clone, Default)]
+pub struct MemoryRepository {
+    state: Arc<Mutex<MemoryState>>,
+}
+impl MemoryRepository {
+    pub async fn insert_user(&self, user: User) {
+        self.state.lock().await.users.insert(user.id.clone(), user);
+    }
+    pub async fn snapshot(&self) -> MemoryState {
+        self.state.lock().await.clone()
+    }
+}
+
+pub struct MemoryTransaction {
+    guard: OwnedMutexGuard<MemoryState>,
+    state: MemoryState,
+}
+
+impl Repository for MemoryRepository {
+    type Transaction = MemoryTransaction;
+
+    async fn begin(&self) -> sqlx::Result<MemoryTransaction> {
+        let guard = self.state.clone().lock_owned().await;
+        let state = guard.clone();
+        Ok(MemoryTransaction { guard, state })
+    }
+    async fn list_users_by_ids(&self, ids: &[String]) -> sqlx::Result<Vec<User>> {
+        let state = self.state.lock().await;
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.users.get(id).cloned())
+            .collect())
+    }
+    async fn list_bets(&self) -> sqlx::Result<Vec<Bet>> {
+        Ok(self.state.lock().await.bets.values().cloned().collect())
+    }
+    async fn list_events_after(&self, after_seq: i64) -> sqlx::Result<Vec<Event>> {
+        let state = self.state.lock().await;
+        Ok(state
+            .events
+            .iter()
+            .filter(|event| event.seq > after_seq)
+            .take(100)
+            .cloned()
+            .collect())
+    }
+}
+
+impl RepositoryTransaction for MemoryTransaction {
+    async fn commit(mut self) -> sqlx::Result<()> {
+        *self.guard = self.state;
+        Ok(())
+    }
+
+    async fn get_user_for_update(&mut self, id: &str) -> sqlx::Result<Option<User>> {
+        Ok(self.state.users.get(id).cloned())
+    }
+    async fn list_users_for_update(&mut self) -> sqlx::Result<Vec<User>> {
+        Ok(self.state.users.values().cloned().collect())
+    }
+    async fn add_money(&mut self, user_id: &str, amount: f64) -> sqlx::Result<()> {
+        if let Some(user) = self.state.users.get_mut(user_id) {
+            user.money += amount;
+        }
+        Ok(())
+    }
+
+    async fn get_bet_for_update(&mut self, id: &str) -> sqlx::Result<Option<Bet>> {
+        Ok(self.state.bets.get(id).cloned())
+    }
+    async fn insert_bet(&mut self, bet: Bet) -> sqlx::Result<()> {
+        self.state.bets.insert(bet.id.clone(), bet);
+        Ok(())
+    }
+    async fn update_bet_details(
+        &mut self,
+        id: &str,
+        description: &str,
+        resolution_criteria: &str,
+    ) -> sqlx::Result<()> {
+        if let Some(bet) = self.state.bets.get_mut(id) {
+            bet.description = description.to_string();
+            bet.resolution_criteria = resolution_criteria.to_string();
+        }
+        Ok(())
+    }
+    async fn update_bet_pools(
+        &mut self,
+        id: &str,
+        yes_pool: f64,
+        no_pool: f64,
+    ) -> sqlx::Result<()> {
+        if let Some(bet) = self.state.bets.get_mut(id) {
+            bet.yes_pool = yes_pool;
+            bet.no_pool = no_pool;
+        }
+        Ok(())
+    }
+    async fn close_bet(&mut self, id: &str) -> sqlx::Result<()> {
+        if let Some(bet) = self.state.bets.get_mut(id) {
+            bet.closed = true;
+        }
+        Ok(())
+    }
+    async fn delete_bet(&mut self, id: &str) -> sqlx::Result<()> {
+        // user_bets cascade, like the foreign key
+        self.state.bets.remove(id);
+        self.state
+            .user_bets
+            .retain(|user_bet| user_bet.bet_id != id);
+        Ok(())
+    }
+
+    async fn get_user_bet_for_update(
+        &mut self,
+        user_id: &str,
+        bet_id: &str,
+        is_yes: bool,
+    ) -> sqlx::Result<Option<UserBet>> {
+        Ok(self
+            .state
+            .user_bets
+            .iter()
+            .find(|user_bet| {
+                user_bet.user_id == user_id
+                    && user_bet.bet_id == bet_id
+                    && user_bet.is_yes == is_yes
+            })
+            .cloned())
+    }
+    async fn list_user_bets_for_update(&mut self, bet_id: &str) -> sqlx::Result<Vec<UserBet>> {
+        Ok(self
+            .state
+            .user_bets
+            .iter()
+            .filter(|user_bet| user_bet.bet_id == bet_id)
+            .cloned()
+            .collect())
+    }
+    async fn save_user_bet(&mut self, user_bet: UserBet) -> sqlx::Result<()> {
+        match self.state.user_bets.iter_mut().find(|existing| {
+            existing.user_id == user_bet.user_id
+                && existing.bet_id == user_bet.bet_id
+                && existing.is_yes == user_bet.is_yes
+        }) {
+            Some(existing) => *existing = user_bet,
+            None => self.state.user_bets.push(user_bet),
+        }
+        Ok(())
+    }
+
+    async fn insert_event(
+        &mut self,
+        actor: Option<&User>,
+        bet_id: Option<&str>,
+        payload: EventPayload,
+    ) -> sqlx::Result<Event> {
+        let event = Event {
+            id: Uuid::new_v4().to_string(),
+            // Transactions are serialized, so they're committed in the order events are inserted
+            seq: self.state.events.len() as i64 + 1,
+            // Postgres only keeps microseconds
+            created_at: Utc::now().trunc_subsecs(6),
+            actor_id: actor.map(|actor| actor.id.clone()),
+            actor_name: actor.map(|actor| actor.name.clone()),
+            bet_id: bet_id.map(str::to_string),
+            payload: Json(payload),
+        };
+        self.state.events.push(event.clone());
+        Ok(event)
+    }
+    async fn has_trades(&mut self, bet_id: &str) -> sqlx::Result<bool> {
+        Ok(self.state.events.iter().any(|event| {
+            event.bet_id.as_deref() == Some(bet_id)
+                && matches!(
+                    event.payload.0,
+                    EventPayload::BetPlaced { .. } | EventPayload::SharesSold { .. }
+                )
+        }))
+    }
+    async fn insert_payout(&mut self, payout: &market::Payout) -> sqlx::Result<()> {
+        self.state.payouts.push(payout.clone());
+        Ok(())
+    }
+    async fn insert_notifications(
+        &mut self,
+        notifications: &[NewNotification],
+    ) -> sqlx::Result<Vec<Notification>> {
+        let mut inserted = vec![];
+        for notification in notifications {
+            if let Some(dedupe_key) = &notification.dedupe_key {
+                if !self
+                    .state
+                    .notification_keys
+                    .insert((notification.user_id.clone(), dedupe_key.clone()))
+                {
+                    continue;
+                }
+            }
+            inserted.push(Notification {
+                id: Uuid::new_v4().to_string(),
+                user_id: notification.user_id.clone(),
+                created_at: Utc::now(),
+                kind: notification.kind.to_string(),
+                bet_id: notification.bet_id.clone(),
+                message: notification.message.clone(),
+                link: notification.link.clone(),
+                read_at: None,
+            });
+        }
+        self.state.notifications.extend(inserted.iter().cloned());
+        Ok(inserted)
+    }
+}
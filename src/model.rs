@@ -1,8 +1,12 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
 
 use serde::{Deserialize, Serialize};
 use sqlx::types::chrono::{DateTime, Utc};
-use sqlx::{FromRow, Pool, Postgres, Transaction};
+use sqlx::types::Json;
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder, Transaction};
+use uuid::Uuid;
+
+use crate::market;
 
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 pub enum YesOrNo {
@@ -26,7 +30,7 @@ impl YesOrNo {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 pub enum YesOrNoOrNA {
     Yes,
     No,
@@ -42,49 +46,94 @@ impl Display for YesOrNoOrNA {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, FromRow)]
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
 pub struct User {
     pub id: String,
     pub name: String,
     pub money: f64,
+    /// Can hide other people's comments
+    pub moderator: bool,
+    pub email: Option<String>,
+    /// Whether notifications are also sent to `email`
+    pub email_notifications: bool,
 }
 impl User {
-    pub async fn list(pool: &Pool<Postgres>) -> Vec<Self> {
+    /// There's just the one admin, who can hand out money and start seasons
+    pub fn is_admin(&self) -> bool {
+        self.name == "Jefferson"
+    }
+    pub fn is_moderator(&self) -> bool {
+        self.moderator || self.is_admin()
+    }
+
+    pub async fn list(pool: &Pool<Postgres>) -> sqlx::Result<Vec<Self>> {
         sqlx::query_as("SELECT * FROM betting.users")
             .fetch_all(pool)
             .await
-            .unwrap()
     }
-    pub async fn list_for_update(transaction: &mut Transaction<'_, Postgres>) -> Vec<Self> {
+    pub async fn list_for_update(
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> sqlx::Result<Vec<Self>> {
         sqlx::query_as("SELECT * FROM betting.users FOR UPDATE")
             .fetch_all(&mut **transaction)
             .await
-            .unwrap()
     }
-    pub async fn get_by_id(pool: &Pool<Postgres>, id: &str) -> Option<Self> {
+    pub async fn get_by_id(pool: &Pool<Postgres>, id: &str) -> sqlx::Result<Option<Self>> {
         sqlx::query_as("SELECT * FROM betting.users WHERE id = $1")
             .bind(id)
             .fetch_optional(pool)
             .await
-            .unwrap()
     }
     pub async fn get_for_update_by_id(
         transaction: &mut Transaction<'_, Postgres>,
         id: &str,
-    ) -> Option<Self> {
+    ) -> sqlx::Result<Option<Self>> {
         sqlx::query_as("SELECT * FROM betting.users WHERE id = $1 FOR UPDATE")
             .bind(id)
             .fetch_optional(&mut **transaction)
             .await
-            .unwrap()
     }
-    pub async fn add_money(transaction: &mut Transaction<'_, Postgres>, id: &str, new_money: f64) {
+    pub async fn add_money(
+        transaction: &mut Transaction<'_, Postgres>,
+        id: &str,
+        new_money: f64,
+    ) -> sqlx::Result<()> {
         sqlx::query("UPDATE betting.users SET money = money + $1 WHERE id = $2")
             .bind(new_money)
             .bind(id)
             .execute(&mut **transaction)
+            .await?;
+        Ok(())
+    }
+    pub async fn set_all_money(
+        transaction: &mut Transaction<'_, Postgres>,
+        money: f64,
+    ) -> sqlx::Result<()> {
+        sqlx::query("UPDATE betting.users SET money = $1")
+            .bind(money)
+            .execute(&mut **transaction)
+            .await?;
+        Ok(())
+    }
+    pub async fn list_by_ids(pool: &Pool<Postgres>, ids: &[String]) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.users WHERE id = ANY($1)")
+            .bind(ids)
+            .fetch_all(pool)
             .await
-            .unwrap();
+    }
+    pub async fn update_email_settings(
+        pool: &Pool<Postgres>,
+        id: &str,
+        email: Option<&str>,
+        email_notifications: bool,
+    ) -> sqlx::Result<()> {
+        sqlx::query("UPDATE betting.users SET email = $1, email_notifications = $2 WHERE id = $3")
+            .bind(email)
+            .bind(email_notifications)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
     }
 }
 
@@ -98,18 +147,30 @@ pub struct UserBet {
     pub spent: f64,
 }
 impl UserBet {
-    pub async fn list(pool: &Pool<Postgres>) -> Vec<Self> {
+    pub async fn list(pool: &Pool<Postgres>) -> sqlx::Result<Vec<Self>> {
         sqlx::query_as("SELECT * FROM betting.user_bets")
             .fetch_all(pool)
             .await
-            .unwrap()
+    }
+    pub async fn list_for_update(
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.user_bets FOR UPDATE")
+            .fetch_all(&mut **transaction)
+            .await
+    }
+    pub async fn list_by_user_id(pool: &Pool<Postgres>, user_id: &str) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.user_bets WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
     }
     pub async fn get_for_update(
         transaction: &mut Transaction<'_, Postgres>,
         user_id: &str,
         bet_id: &str,
         is_yes: bool,
-    ) -> Option<Self> {
+    ) -> sqlx::Result<Option<Self>> {
         sqlx::query_as(
             "SELECT * FROM betting.user_bets WHERE user_id = $1 AND bet_id = $2 AND is_yes = $3 FOR UPDATE",
         )
@@ -118,9 +179,8 @@ impl UserBet {
         .bind(is_yes)
         .fetch_optional(&mut **transaction)
         .await
-        .unwrap()
     }
-    pub async fn insert(self, transaction: &mut Transaction<'_, Postgres>) {
+    pub async fn insert(self, transaction: &mut Transaction<'_, Postgres>) -> sqlx::Result<()> {
         sqlx::query("INSERT INTO betting.user_bets (user_id, bet_id, is_yes, amount, spent) VALUES ($1, $2, $3, $4, $5)")
             .bind(self.user_id)
             .bind(self.bet_id)
@@ -129,9 +189,13 @@ impl UserBet {
             .bind(self.spent)
             .execute(&mut **transaction)
             .await
-            .unwrap();
+            ?;
+        Ok(())
     }
-    pub async fn update_or_insert(self, transaction: &mut Transaction<'_, Postgres>) {
+    pub async fn update_or_insert(
+        self,
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> sqlx::Result<()> {
         sqlx::query("INSERT INTO betting.user_bets (user_id, bet_id, is_yes, amount, spent) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (user_id, bet_id, is_yes) DO UPDATE SET amount = $4, spent = $5")
             .bind(self.user_id)
             .bind(self.bet_id)
@@ -140,18 +204,24 @@ impl UserBet {
             .bind(self.spent)
             .execute(&mut **transaction)
             .await
-            .unwrap();
+            ?;
+        Ok(())
     }
 
+    pub async fn list_by_bet_id(pool: &Pool<Postgres>, bet_id: &str) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.user_bets WHERE bet_id = $1")
+            .bind(bet_id)
+            .fetch_all(pool)
+            .await
+    }
     pub async fn get_for_update_by_bet_id(
         transaction: &mut Transaction<'_, Postgres>,
         bet_id: &str,
-    ) -> Vec<UserBet> {
+    ) -> sqlx::Result<Vec<UserBet>> {
         sqlx::query_as("SELECT * FROM betting.user_bets WHERE bet_id = $1")
             .bind(bet_id)
             .fetch_all(&mut **transaction)
             .await
-            .unwrap()
     }
 }
 
@@ -162,87 +232,1109 @@ pub struct Bet {
     #[sqlx(try_from = "i32")]
     pub created_seconds_since_epoch: usize,
     pub name: String,
+    /// Markdown
+    pub description: String,
+    /// Markdown, spelling out what counts as yes or no
+    pub resolution_criteria: String,
+    pub tags: Vec<String>,
+    /// When trading stops, if the creator picked a time. They can still close it early
+    pub closes_at: Option<DateTime<Utc>>,
     pub closed: bool,
     pub yes_pool: f64,
     pub no_pool: f64,
+    /// What the creator put in to fund the pools, which is theirs rather than a position
+    pub liquidity: f64,
 }
 impl Bet {
-    pub async fn list(pool: &Pool<Postgres>) -> Vec<Self> {
+    pub fn probability_of_yes(&self) -> f64 {
+        self.no_pool / (self.yes_pool + self.no_pool)
+    }
+    /// Whether trading has stopped, either because the creator closed it or its closing time passed
+    pub fn is_closed(&self) -> bool {
+        self.is_closed_at(Utc::now())
+    }
+    pub fn is_closed_at(&self, now: DateTime<Utc>) -> bool {
+        self.closed || self.closes_at.is_some_and(|closes_at| closes_at <= now)
+    }
+
+    pub async fn list(pool: &Pool<Postgres>) -> sqlx::Result<Vec<Self>> {
         sqlx::query_as("SELECT * FROM betting.bets")
             .fetch_all(pool)
             .await
-            .unwrap()
+    }
+    pub async fn list_for_update(
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.bets FOR UPDATE")
+            .fetch_all(&mut **transaction)
+            .await
+    }
+    pub async fn search(pool: &Pool<Postgres>, search: &BetSearch) -> sqlx::Result<Vec<Self>> {
+        let mut query = QueryBuilder::new("SELECT bets.* FROM betting.bets");
+        if search.sort == BetSort::MostTraded {
+            query.push(
+                " LEFT JOIN (SELECT bet_id, count(*) AS trades FROM betting.events WHERE kind IN ('BetPlaced', 'SharesSold') GROUP BY bet_id) trades ON trades.bet_id = bets.id",
+            );
+        }
+        query.push(" WHERE true");
+        if let Some(text) = &search.text {
+            query
+                .push(" AND search_vector @@ websearch_to_tsquery('english', ")
+                .push_bind(text)
+                .push(")");
+        }
+        if let Some(tag) = &search.tag {
+            query.push(" AND ").push_bind(tag).push(" = ANY(tags)");
+        }
+        query.push(match search.sort {
+            BetSort::Newest => " ORDER BY created_seconds_since_epoch DESC",
+            BetSort::MostTraded => {
+                " ORDER BY coalesce(trades.trades, 0) DESC, created_seconds_since_epoch DESC"
+            }
+            BetSort::ClosingSoon => {
+                " ORDER BY (closed OR closes_at <= now()) ASC, closes_at ASC NULLS LAST, created_seconds_since_epoch DESC"
+            }
+            BetSort::MostUncertain => {
+                " ORDER BY abs(no_pool / (yes_pool + no_pool) - 0.5) ASC, created_seconds_since_epoch DESC"
+            }
+        });
+
+        query.build_query_as().fetch_all(pool).await
+    }
+    pub async fn get_by_id(pool: &Pool<Postgres>, id: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM betting.bets WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+    pub async fn list_ids(pool: &Pool<Postgres>) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar("SELECT id FROM betting.bets")
+            .fetch_all(pool)
+            .await
+    }
+    /// Open markets whose closing time falls in [from, to)
+    pub async fn list_closing_between(
+        pool: &Pool<Postgres>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM betting.bets WHERE NOT closed AND closes_at >= $1 AND closes_at < $2",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(pool)
+        .await
+    }
+    /// Every tag that's on at least one market, alphabetically
+    pub async fn list_tags(pool: &Pool<Postgres>) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar("SELECT DISTINCT unnest(tags) AS tag FROM betting.bets ORDER BY tag")
+            .fetch_all(pool)
+            .await
     }
     pub async fn get_for_update_by_id(
         transaction: &mut Transaction<'_, Postgres>,
         id: &str,
-    ) -> Option<Self> {
+    ) -> sqlx::Result<Option<Self>> {
         sqlx::query_as("SELECT * FROM betting.bets WHERE id = $1 FOR UPDATE")
             .bind(id)
             .fetch_optional(&mut **transaction)
             .await
-            .unwrap()
     }
-    pub async fn insert(self, transaction: &mut Transaction<'_, Postgres>) {
-        sqlx::query("INSERT INTO betting.bets (id, creator_id, created_seconds_since_epoch, name, closed, yes_pool, no_pool) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+    pub async fn insert(self, transaction: &mut Transaction<'_, Postgres>) -> sqlx::Result<()> {
+        sqlx::query("INSERT INTO betting.bets (id, creator_id, created_seconds_since_epoch, name, description, resolution_criteria, tags, closes_at, closed, yes_pool, no_pool, liquidity) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)")
             .bind(self.id)
             .bind(self.creator_id)
             .bind(self.created_seconds_since_epoch as i64)
             .bind(self.name)
+            .bind(self.description)
+            .bind(self.resolution_criteria)
+            .bind(self.tags)
+            .bind(self.closes_at)
             .bind(self.closed)
             .bind(self.yes_pool)
             .bind(self.no_pool)
+            .bind(self.liquidity)
             .execute(&mut **transaction)
             .await
-            .unwrap();
+            ?;
+        Ok(())
     }
-    pub async fn delete(transaction: &mut Transaction<'_, Postgres>, id: &str) {
+    pub async fn delete(transaction: &mut Transaction<'_, Postgres>, id: &str) -> sqlx::Result<()> {
         sqlx::query("DELETE FROM betting.bets WHERE id = $1")
             .bind(id)
             .execute(&mut **transaction)
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
+    }
+    pub async fn update_details(
+        transaction: &mut Transaction<'_, Postgres>,
+        id: &str,
+        description: &str,
+        resolution_criteria: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE betting.bets SET description = $1, resolution_criteria = $2 WHERE id = $3",
+        )
+        .bind(description)
+        .bind(resolution_criteria)
+        .bind(id)
+        .execute(&mut **transaction)
+        .await?;
+        Ok(())
     }
-    pub async fn close(transaction: &mut Transaction<'_, Postgres>, id: &str) {
+    pub async fn close(transaction: &mut Transaction<'_, Postgres>, id: &str) -> sqlx::Result<()> {
         sqlx::query("UPDATE betting.bets SET closed = true WHERE id = $1")
             .bind(id)
             .execute(&mut **transaction)
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
     }
     pub async fn update_pools(
         transaction: &mut Transaction<'_, Postgres>,
         id: &str,
         yes_pool: f64,
         no_pool: f64,
-    ) {
+    ) -> sqlx::Result<()> {
         sqlx::query("UPDATE betting.bets SET yes_pool = $1, no_pool = $2 WHERE id = $3")
             .bind(yes_pool)
             .bind(no_pool)
             .bind(id)
             .execute(&mut **transaction)
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BetSort {
+    #[default]
+    Newest,
+    /// Most shares bought or sold
+    MostTraded,
+    /// Open markets with the nearest closing time first
+    ClosingSoon,
+    /// Probability closest to 50%
+    MostUncertain,
+}
+impl BetSort {
+    pub const OPTIONS: [(BetSort, &'static str); 4] = [
+        (BetSort::Newest, "Newest"),
+        (BetSort::MostTraded, "Most traded"),
+        (BetSort::ClosingSoon, "Closing soon"),
+        (BetSort::MostUncertain, "Most uncertain"),
+    ];
+}
+
+#[derive(Debug, Default)]
+pub struct BetSearch {
+    /// Full-text search over names and descriptions, in websearch syntax (quotes, "or", -exclusions)
+    pub text: Option<String>,
+    pub tag: Option<String>,
+    pub sort: BetSort,
+}
+
+/// Everything that can happen to the markets, along with whatever is needed to describe it after the
+/// fact (e.g. bets are deleted when they're resolved, so their name is kept here)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum EventPayload {
+    BetPlaced {
+        bet_name: String,
+        which: YesOrNo,
+        amount: usize,
+        spent: f64,
+        /// The market's probability once the bet went through. Missing for bets placed before this
+        /// was recorded
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        probability_of_yes_after: Option<f64>,
+    },
+    SharesSold {
+        bet_name: String,
+        which: YesOrNo,
+        amount: usize,
+        proceeds: f64,
+        probability_of_yes_after: f64,
+    },
+    MarketCreated {
+        bet_name: String,
+        starting_money: usize,
+    },
+    /// The description or resolution criteria changed. The old versions are kept so that traders can
+    /// see what they originally bet on
+    MarketEdited {
+        bet_name: String,
+        previous_description: String,
+        previous_resolution_criteria: String,
+        description: String,
+        resolution_criteria: String,
+        /// Edits before anybody has traded are free, since nobody could have been misled
+        after_first_trade: bool,
+    },
+    MarketClosed {
+        bet_name: String,
+    },
+    CommentPosted {
+        bet_name: String,
+        comment_id: String,
+        /// The author of the comment being replied to, if it's a reply
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to_user_id: Option<String>,
+    },
+    MarketResolved {
+        bet_name: String,
+        result: YesOrNoOrNA,
+    },
+    MoneyGranted {
+        amount: f64,
+    },
+    SeasonStarted {
+        season_name: String,
+        starting_money: f64,
+        /// Whether markets that were still open were resolved N/A rather than carried over
+        resolved_open_markets: bool,
+    },
+    /// Free-text log messages from before events were structured
+    Legacy {
+        content: String,
+    },
+}
+impl EventPayload {
+    pub const KINDS: [&'static str; 10] = [
+        "BetPlaced",
+        "SharesSold",
+        "MarketCreated",
+        "MarketEdited",
+        "MarketClosed",
+        "CommentPosted",
+        "MarketResolved",
+        "MoneyGranted",
+        "SeasonStarted",
+        "Legacy",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EventPayload::BetPlaced { .. } => "BetPlaced",
+            EventPayload::SharesSold { .. } => "SharesSold",
+            EventPayload::MarketCreated { .. } => "MarketCreated",
+            EventPayload::MarketEdited { .. } => "MarketEdited",
+            EventPayload::MarketClosed { .. } => "MarketClosed",
+            EventPayload::CommentPosted { .. } => "CommentPosted",
+            EventPayload::MarketResolved { .. } => "MarketResolved",
+            EventPayload::MoneyGranted { .. } => "MoneyGranted",
+            EventPayload::SeasonStarted { .. } => "SeasonStarted",
+            EventPayload::Legacy { .. } => "Legacy",
+        }
     }
 }
 
+/// Advisory lock key for inserting events
+const EVENT_SEQ_LOCK: i64 = 0x6576656e7473;
+
 #[derive(Debug, Clone, FromRow)]
-pub struct LogMessage {
+pub struct Event {
+    pub id: String,
+    /// Commit order, which `created_at` isn't since it's when the transaction started
+    pub seq: i64,
     pub created_at: DateTime<Utc>,
-    pub content: String,
+    pub actor_id: Option<String>,
+    /// Only populated when the event is fetched from the database with the actor's user joined in
+    #[sqlx(default)]
+    pub actor_name: Option<String>,
+    pub bet_id: Option<String>,
+    pub payload: Json<EventPayload>,
 }
-impl LogMessage {
-    pub async fn list(pool: &Pool<Postgres>) -> Vec<Self> {
-        sqlx::query_as("SELECT * FROM betting.logs order by created_at DESC LIMIT 100")
+impl Event {
+    /// Human-readable description of the event, for the dashboard's log
+    pub fn describe(&self) -> String {
+        let actor_name = self.actor_name.as_deref().unwrap_or("Somebody");
+        match &self.payload.0 {
+            EventPayload::BetPlaced {
+                bet_name,
+                which,
+                amount,
+                spent,
+                ..
+            } => format!("{actor_name} bought {amount} {which} shares in \"{bet_name}\" for ${spent}"),
+            EventPayload::SharesSold {
+                bet_name,
+                which,
+                amount,
+                proceeds,
+                ..
+            } => format!("{actor_name} sold {amount} {which} shares in \"{bet_name}\" for ${proceeds}"),
+            EventPayload::MarketCreated {
+                bet_name,
+                starting_money,
+            } => format!(
+                "{actor_name} created a new market, \"{bet_name}\", with a starting pool of {starting_money}"
+            ),
+            EventPayload::MarketEdited { bet_name, .. } => {
+                format!("{actor_name} edited the description of \"{bet_name}\"")
+            }
+            EventPayload::MarketClosed { bet_name } => {
+                format!("{actor_name} closed the market \"{bet_name}\"")
+            }
+            EventPayload::CommentPosted {
+                bet_name,
+                reply_to_user_id,
+                ..
+            } => {
+                if reply_to_user_id.is_some() {
+                    format!("{actor_name} replied to a comment on \"{bet_name}\"")
+                } else {
+                    format!("{actor_name} commented on \"{bet_name}\"")
+                }
+            }
+            EventPayload::MarketResolved { bet_name, result } => {
+                format!("{actor_name} resolved the market \"{bet_name}\" with a result of {result}")
+            }
+            EventPayload::MoneyGranted { amount } => {
+                format!("${amount} has been added to everybody's account")
+            }
+            EventPayload::SeasonStarted {
+                season_name,
+                starting_money,
+                resolved_open_markets,
+            } => format!(
+                "{season_name} has started - everybody's cash has been reset to ${starting_money}{}",
+                if *resolved_open_markets {
+                    " and open markets were resolved N/A"
+                } else {
+                    ""
+                }
+            ),
+            EventPayload::Legacy { content } => content.clone(),
+        }
+    }
+
+    pub async fn list(pool: &Pool<Postgres>) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT events.*, users.name AS actor_name FROM betting.events LEFT JOIN betting.users ON users.id = events.actor_id ORDER BY seq DESC LIMIT 100",
+        )
+        .fetch_all(pool)
+        .await
+    }
+    /// Up to 100 of the events committed after `after_seq`, oldest first, so that polling from the
+    /// last one returned never skips any
+    pub async fn list_after(pool: &Pool<Postgres>, after_seq: i64) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT events.*, users.name AS actor_name FROM betting.events LEFT JOIN betting.users ON users.id = events.actor_id WHERE seq > $1 ORDER BY seq LIMIT 100",
+        )
+        .bind(after_seq)
+        .fetch_all(pool)
+        .await
+    }
+    /// Whether anybody has ever bought or sold shares in the bet, even if they've all sold out since
+    pub async fn has_trades(
+        transaction: &mut Transaction<'_, Postgres>,
+        bet_id: &str,
+    ) -> sqlx::Result<bool> {
+        sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM betting.events WHERE bet_id = $1 AND kind IN ('BetPlaced', 'SharesSold'))",
+        )
+        .bind(bet_id)
+        .fetch_one(&mut **transaction)
+        .await
+    }
+    pub async fn earliest_created_at(pool: &Pool<Postgres>) -> sqlx::Result<Option<DateTime<Utc>>> {
+        sqlx::query_scalar("SELECT min(created_at) FROM betting.events")
+            .fetch_one(pool)
+            .await
+    }
+    /// Newest first, starting after the cursor if there is one
+    pub async fn list_filtered(
+        pool: &Pool<Postgres>,
+        filter: &EventFilter,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> sqlx::Result<Vec<Self>> {
+        let mut query = QueryBuilder::new(
+            "SELECT events.*, users.name AS actor_name FROM betting.events LEFT JOIN betting.users ON users.id = events.actor_id WHERE true",
+        );
+        if let Some(actor_id) = &filter.actor_id {
+            query.push(" AND actor_id = ").push_bind(actor_id);
+        }
+        if let Some(bet_id) = &filter.bet_id {
+            query.push(" AND bet_id = ").push_bind(bet_id);
+        }
+        if let Some(kind) = &filter.kind {
+            query.push(" AND kind = ").push_bind(kind);
+        }
+        if let Some(from) = filter.from {
+            query.push(" AND created_at >= ").push_bind(from);
+        }
+        if let Some(to) = filter.to {
+            query.push(" AND created_at < ").push_bind(to);
+        }
+        if let Some(cursor) = cursor {
+            query
+                .push(" AND (created_at, events.id) < (")
+                .push_bind(cursor.created_at)
+                .push(", ")
+                .push_bind(&cursor.id)
+                .push(")");
+        }
+        query
+            .push(" ORDER BY created_at DESC, events.id DESC LIMIT ")
+            .push_bind(limit as i64);
+
+        query.build_query_as().fetch_all(pool).await
+    }
+    pub async fn insert(
+        transaction: &mut Transaction<'_, Postgres>,
+        actor: Option<&User>,
+        bet_id: Option<&str>,
+        payload: EventPayload,
+    ) -> sqlx::Result<Self> {
+        // Held until the transaction ends, so that events are committed in seq order
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(EVENT_SEQ_LOCK)
+            .execute(&mut **transaction)
+            .await?;
+        let mut event: Self = sqlx::query_as(
+            "INSERT INTO betting.events (id, kind, actor_id, bet_id, payload) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(payload.kind())
+        .bind(actor.map(|actor| &actor.id))
+        .bind(bet_id)
+        .bind(Json(payload))
+        .fetch_one(&mut **transaction)
+        .await?;
+
+        event.actor_name = actor.map(|actor| actor.name.clone());
+
+        if Webhook::EVENT_KINDS.contains(&event.payload.kind()) {
+            WebhookDelivery::insert_for_event(transaction, &event).await?;
+        }
+
+        Ok(event)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub actor_id: Option<String>,
+    pub bet_id: Option<String>,
+    pub kind: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Position in the (created_at, id) ordering of events, so that pages stay stable as new events come in
+#[derive(Debug, Clone)]
+pub struct EventCursor {
+    pub created_at: DateTime<Utc>,
+    pub id: String,
+}
+impl EventCursor {
+    pub fn after(event: &Event) -> Self {
+        Self {
+            created_at: event.created_at,
+            id: event.id.clone(),
+        }
+    }
+    pub fn parse(cursor: &str) -> Option<Self> {
+        let (micros, id) = cursor.split_once('_')?;
+        Some(Self {
+            created_at: DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+            id: id.to_string(),
+        })
+    }
+}
+impl Display for EventCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_{}", self.created_at.timestamp_micros(), self.id)
+    }
+}
+
+/// What a user got back from a market when it was resolved. Kept around because bets (and their
+/// user_bets) are deleted on resolution
+#[derive(Debug, Clone, FromRow)]
+pub struct Payout {
+    pub bet_id: String,
+    pub bet_name: String,
+    pub result: String,
+    pub spent: f64,
+    pub payout: f64,
+    pub resolved_at: DateTime<Utc>,
+}
+impl Payout {
+    pub async fn list_by_user_id(pool: &Pool<Postgres>, user_id: &str) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.payouts WHERE user_id = $1 ORDER BY resolved_at DESC")
+            .bind(user_id)
             .fetch_all(pool)
             .await
-            .unwrap()
     }
-    pub async fn insert(pool: &Pool<Postgres>, content: &str) {
-        sqlx::query("INSERT INTO betting.logs (content) VALUES ($1)")
+    pub async fn insert(
+        transaction: &mut Transaction<'_, Postgres>,
+        payout: &market::Payout,
+    ) -> sqlx::Result<()> {
+        sqlx::query("INSERT INTO betting.payouts (bet_id, user_id, bet_name, result, spent, payout) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(&payout.bet_id)
+            .bind(&payout.user_id)
+            .bind(&payout.bet_name)
+            .bind(payout.result.to_string())
+            .bind(payout.spent)
+            .bind(payout.payout)
+            .execute(&mut **transaction)
+            .await
+            ?;
+        Ok(())
+    }
+}
+
+/// A user's expected money at a point in time, for tracking how it changes
+#[derive(Debug, Clone, FromRow)]
+pub struct NetWorthSnapshot {
+    pub user_id: String,
+    pub net_worth: f64,
+}
+impl NetWorthSnapshot {
+    pub async fn latest_taken_at(pool: &Pool<Postgres>) -> sqlx::Result<Option<DateTime<Utc>>> {
+        sqlx::query_scalar("SELECT max(taken_at) FROM betting.net_worth_snapshots")
+            .fetch_one(pool)
+            .await
+    }
+    /// Oldest first
+    pub async fn list_since(
+        pool: &Pool<Postgres>,
+        since: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM betting.net_worth_snapshots WHERE $1::timestamptz IS NULL OR taken_at >= $1 ORDER BY taken_at",
+        )
+        .bind(since)
+        .fetch_all(pool)
+        .await
+    }
+    /// Each user's most recent snapshot from at or before the given time
+    pub async fn list_latest_at(
+        pool: &Pool<Postgres>,
+        at: DateTime<Utc>,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT DISTINCT ON (user_id) * FROM betting.net_worth_snapshots WHERE taken_at <= $1 ORDER BY user_id, taken_at DESC",
+        )
+        .bind(at)
+        .fetch_all(pool)
+        .await
+    }
+    pub async fn insert_all(
+        pool: &Pool<Postgres>,
+        taken_at: DateTime<Utc>,
+        net_worths: &BTreeMap<String, f64>,
+    ) -> sqlx::Result<()> {
+        Self::insert(pool, taken_at, None, net_worths).await
+    }
+    /// The regular snapshot for `taken_at`'s hour. If one has already been taken for that hour (like
+    /// by another request at the same time), this does nothing
+    pub async fn insert_hourly(
+        pool: &Pool<Postgres>,
+        taken_at: DateTime<Utc>,
+        net_worths: &BTreeMap<String, f64>,
+    ) -> sqlx::Result<()> {
+        let hourly_bucket = taken_at.timestamp().div_euclid(60 * 60);
+        Self::insert(pool, taken_at, Some(hourly_bucket), net_worths).await
+    }
+    async fn insert(
+        pool: &Pool<Postgres>,
+        taken_at: DateTime<Utc>,
+        hourly_bucket: Option<i64>,
+        net_worths: &BTreeMap<String, f64>,
+    ) -> sqlx::Result<()> {
+        if net_worths.is_empty() {
+            return Ok(());
+        }
+        QueryBuilder::new(
+            "INSERT INTO betting.net_worth_snapshots (user_id, taken_at, net_worth, hourly_bucket) ",
+        )
+        .push_values(net_worths, |mut row, (user_id, net_worth)| {
+            row.push_bind(user_id)
+                .push_bind(taken_at)
+                .push_bind(net_worth)
+                .push_bind(hourly_bucket);
+        })
+        .push(" ON CONFLICT DO NOTHING")
+        .build()
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// A stretch of time that ends with everybody's standings being archived and their cash being reset
+#[derive(Debug, Clone, FromRow)]
+pub struct Season {
+    pub id: i32,
+    pub name: String,
+    pub started_at: DateTime<Utc>,
+    /// None for the current season
+    pub ended_at: Option<DateTime<Utc>>,
+    /// None for the stretch before seasons existed
+    pub starting_money: Option<f64>,
+}
+impl Season {
+    pub async fn list(pool: &Pool<Postgres>) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.seasons ORDER BY started_at DESC")
+            .fetch_all(pool)
+            .await
+    }
+    pub async fn get_by_id(pool: &Pool<Postgres>, id: i32) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM betting.seasons WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+    pub async fn get_current(pool: &Pool<Postgres>) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM betting.seasons WHERE ended_at IS NULL")
+            .fetch_optional(pool)
+            .await
+    }
+    pub async fn get_current_for_update(
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM betting.seasons WHERE ended_at IS NULL FOR UPDATE")
+            .fetch_optional(&mut **transaction)
+            .await
+    }
+    pub async fn insert(
+        transaction: &mut Transaction<'_, Postgres>,
+        name: &str,
+        started_at: DateTime<Utc>,
+        starting_money: Option<f64>,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "INSERT INTO betting.seasons (name, started_at, starting_money) VALUES ($1, $2, $3) RETURNING *",
+        )
+        .bind(name)
+        .bind(started_at)
+        .bind(starting_money)
+        .fetch_one(&mut **transaction)
+        .await
+    }
+    pub async fn end(
+        transaction: &mut Transaction<'_, Postgres>,
+        id: i32,
+        ended_at: DateTime<Utc>,
+    ) -> sqlx::Result<()> {
+        sqlx::query("UPDATE betting.seasons SET ended_at = $1 WHERE id = $2")
+            .bind(ended_at)
+            .bind(id)
+            .execute(&mut **transaction)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Where a user finished when a season ended. The name is copied so that the standings don't change
+/// if the user renames themselves later
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct SeasonStanding {
+    pub season_id: i32,
+    pub user_id: String,
+    pub user_name: String,
+    pub rank: i32,
+    pub liquid_money: f64,
+    pub expected_money: f64,
+    pub max_money: f64,
+}
+impl SeasonStanding {
+    pub async fn list_by_season_id(
+        pool: &Pool<Postgres>,
+        season_id: i32,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.season_standings WHERE season_id = $1 ORDER BY rank")
+            .bind(season_id)
+            .fetch_all(pool)
+            .await
+    }
+    pub async fn insert_all(
+        transaction: &mut Transaction<'_, Postgres>,
+        standings: &[Self],
+    ) -> sqlx::Result<()> {
+        if standings.is_empty() {
+            return Ok(());
+        }
+        QueryBuilder::new(
+            "INSERT INTO betting.season_standings (season_id, user_id, user_name, rank, liquid_money, expected_money, max_money) ",
+        )
+        .push_values(standings, |mut row, standing| {
+            row.push_bind(standing.season_id)
+                .push_bind(&standing.user_id)
+                .push_bind(&standing.user_name)
+                .push_bind(standing.rank)
+                .push_bind(standing.liquid_money)
+                .push_bind(standing.expected_money)
+                .push_bind(standing.max_money);
+        })
+        .build()
+        .execute(&mut **transaction)
+        .await
+        ?;
+        Ok(())
+    }
+}
+
+/// A comment on a market. Deleted comments keep their row (with the content cleared) so that replies
+/// to them still have somewhere to hang
+#[derive(Debug, Clone, FromRow)]
+pub struct Comment {
+    pub id: String,
+    pub bet_id: String,
+    pub parent_id: Option<String>,
+    pub author_id: String,
+    /// Only populated when the comment is fetched from the database with the author's user joined in
+    #[sqlx(default)]
+    pub author_name: Option<String>,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted: bool,
+    /// The moderator who hid the comment, if it's hidden
+    pub hidden_by: Option<String>,
+}
+impl Comment {
+    /// Oldest first
+    pub async fn list_by_bet_id(pool: &Pool<Postgres>, bet_id: &str) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT comments.*, users.name AS author_name FROM betting.comments JOIN betting.users ON users.id = comments.author_id WHERE bet_id = $1 ORDER BY created_at, comments.id",
+        )
+        .bind(bet_id)
+        .fetch_all(pool)
+        .await
+    }
+    pub async fn get_for_update_by_id(
+        transaction: &mut Transaction<'_, Postgres>,
+        id: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM betting.comments WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut **transaction)
+            .await
+    }
+    pub async fn insert(
+        transaction: &mut Transaction<'_, Postgres>,
+        bet_id: &str,
+        parent_id: Option<&str>,
+        author_id: &str,
+        content: &str,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "INSERT INTO betting.comments (id, bet_id, parent_id, author_id, content) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(bet_id)
+        .bind(parent_id)
+        .bind(author_id)
+        .bind(content)
+        .fetch_one(&mut **transaction)
+        .await
+    }
+    pub async fn update_content(
+        transaction: &mut Transaction<'_, Postgres>,
+        id: &str,
+        content: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query("UPDATE betting.comments SET content = $1, edited_at = now() WHERE id = $2")
             .bind(content)
-            .execute(pool)
+            .bind(id)
+            .execute(&mut **transaction)
+            .await?;
+        Ok(())
+    }
+    pub async fn delete(transaction: &mut Transaction<'_, Postgres>, id: &str) -> sqlx::Result<()> {
+        sqlx::query("UPDATE betting.comments SET content = '', deleted = true WHERE id = $1")
+            .bind(id)
+            .execute(&mut **transaction)
+            .await?;
+        Ok(())
+    }
+    pub async fn set_hidden_by(
+        transaction: &mut Transaction<'_, Postgres>,
+        id: &str,
+        hidden_by: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query("UPDATE betting.comments SET hidden_by = $1 WHERE id = $2")
+            .bind(hidden_by)
+            .bind(id)
+            .execute(&mut **transaction)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Something a user should know about, shown in their inbox and optionally emailed to them
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Notification {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: DateTime<Utc>,
+    pub kind: String,
+    /// Not a foreign key because bets are deleted when they're resolved
+    pub bet_id: Option<String>,
+    pub message: String,
+    /// Where in the app to go to see what happened
+    pub link: String,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+/// A notification that hasn't been stored yet
+#[derive(Debug, Clone)]
+pub struct NewNotification {
+    pub user_id: String,
+    pub kind: &'static str,
+    pub bet_id: Option<String>,
+    pub message: String,
+    pub link: String,
+    /// Notifications with the same key are only ever sent to a user once
+    pub dedupe_key: Option<String>,
+}
+impl Notification {
+    /// Newest first
+    pub async fn list_by_user_id(
+        pool: &Pool<Postgres>,
+        user_id: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM betting.notifications WHERE user_id = $1 ORDER BY created_at DESC, id LIMIT $2",
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    }
+    pub async fn count_unread(pool: &Pool<Postgres>, user_id: &str) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM betting.notifications WHERE user_id = $1 AND read_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+    }
+    pub async fn mark_all_read(pool: &Pool<Postgres>, user_id: &str) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE betting.notifications SET read_at = now() WHERE user_id = $1 AND read_at IS NULL",
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await
+        ?;
+        Ok(())
+    }
+    /// Returns only the notifications that were actually inserted, skipping duplicates
+    pub async fn insert_all(
+        transaction: &mut Transaction<'_, Postgres>,
+        notifications: &[NewNotification],
+    ) -> sqlx::Result<Vec<Self>> {
+        if notifications.is_empty() {
+            return Ok(vec![]);
+        }
+        QueryBuilder::<Postgres>::new(
+            "INSERT INTO betting.notifications (id, user_id, kind, bet_id, message, link, dedupe_key) ",
+        )
+        .push_values(notifications, |mut row, notification| {
+            row.push_bind(Uuid::new_v4().to_string())
+                .push_bind(&notification.user_id)
+                .push_bind(notification.kind)
+                .push_bind(&notification.bet_id)
+                .push_bind(&notification.message)
+                .push_bind(&notification.link)
+                .push_bind(&notification.dedupe_key);
+        })
+        .push(
+            " ON CONFLICT (user_id, dedupe_key) DO NOTHING RETURNING id, user_id, created_at, kind, bet_id, message, link, read_at",
+        )
+        .build_query_as()
+        .fetch_all(&mut **transaction)
+        .await
+    }
+}
+
+/// An admin-configured URL that gets a signed copy of market events. The secret is left out, since
+/// it's only needed when sending deliveries
+#[derive(Debug, Clone, FromRow)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Empty means every kind in `EVENT_KINDS`
+    pub event_kinds: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+impl Webhook {
+    /// The events that can be sent to webhooks
+    pub const EVENT_KINDS: [&'static str; 5] = [
+        "MarketCreated",
+        "BetPlaced",
+        "SharesSold",
+        "MarketClosed",
+        "MarketResolved",
+    ];
+
+    pub async fn list(pool: &Pool<Postgres>) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM betting.webhooks ORDER BY created_at")
+            .fetch_all(pool)
             .await
-            .unwrap();
+    }
+    pub async fn insert(
+        pool: &Pool<Postgres>,
+        url: &str,
+        secret: &str,
+        event_kinds: &[String],
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO betting.webhooks (id, url, secret, event_kinds) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(url)
+        .bind(secret)
+        .bind(event_kinds)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+    pub async fn delete(pool: &Pool<Postgres>, id: &str) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM betting.webhooks WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// One event on its way to one webhook. These double as the delivery log
+#[derive(Debug, Clone, FromRow)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub body: Json<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    pub attempts: i32,
+    /// None once it's been delivered or given up on
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    /// Only populated when the delivery is fetched with its webhook joined in
+    #[sqlx(default)]
+    pub url: Option<String>,
+    #[sqlx(default)]
+    pub secret: Option<String>,
+}
+impl WebhookDelivery {
+    /// Queues the event for every webhook that wants it, in the same transaction as the event itself
+    /// so that nothing is sent for changes that get rolled back
+    pub async fn insert_for_event(
+        transaction: &mut Transaction<'_, Postgres>,
+        event: &Event,
+    ) -> sqlx::Result<()> {
+        let body = serde_json::json!({
+            "id": event.id,
+            "kind": event.payload.kind(),
+            "created_at": event.created_at,
+            "actor_id": event.actor_id,
+            "actor_name": event.actor_name,
+            "bet_id": event.bet_id,
+            "description": event.describe(),
+            "payload": event.payload.0,
+        });
+        sqlx::query(
+            "INSERT INTO betting.webhook_deliveries (id, webhook_id, event_id, body) SELECT gen_random_uuid()::text, id, $1, $2 FROM betting.webhooks WHERE event_kinds = '{}' OR $3 = ANY(event_kinds)",
+        )
+        .bind(&event.id)
+        .bind(Json(body))
+        .bind(event.payload.kind())
+        .execute(&mut **transaction)
+        .await
+        ?;
+        Ok(())
+    }
+    /// Newest first, with their webhook's URL
+    pub async fn list_recent(pool: &Pool<Postgres>, limit: i64) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT webhook_deliveries.*, webhooks.url FROM betting.webhook_deliveries JOIN betting.webhooks ON webhooks.id = webhook_deliveries.webhook_id ORDER BY webhook_deliveries.created_at DESC LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    }
+    /// Takes deliveries that are due, pushing their next attempt back by `lease` so that nothing else
+    /// picks them up while they're being sent
+    pub async fn claim_due(
+        pool: &Pool<Postgres>,
+        lease: chrono::Duration,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "UPDATE betting.webhook_deliveries SET next_attempt_at = now() + $1 FROM betting.webhooks WHERE webhooks.id = webhook_deliveries.webhook_id AND webhook_deliveries.id IN (SELECT id FROM betting.webhook_deliveries WHERE next_attempt_at <= now() ORDER BY next_attempt_at LIMIT $2 FOR UPDATE SKIP LOCKED) RETURNING webhook_deliveries.*, webhooks.url, webhooks.secret",
+        )
+        .bind(lease)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    }
+    /// Records an attempt. `next_attempt_at` is None if there shouldn't be another one
+    pub async fn record_attempt(
+        pool: &Pool<Postgres>,
+        id: &str,
+        delivered: bool,
+        status: Option<i32>,
+        error: Option<&str>,
+        next_attempt_at: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE betting.webhook_deliveries SET attempts = attempts + 1, delivered_at = CASE WHEN $1 THEN now() END, last_status = $2, last_error = $3, next_attempt_at = $4 WHERE id = $5",
+        )
+        .bind(delivered)
+        .bind(status)
+        .bind(error)
+        .bind(next_attempt_at)
+        .bind(id)
+        .execute(pool)
+        .await
+        ?;
+        Ok(())
+    }
+}
+
+/// Which betting user a chat user acts as. Keyed by the chat team and the user's ID within it
+#[derive(Debug, Clone, FromRow)]
+pub struct SlackUser {
+    pub user_id: String,
+}
+impl SlackUser {
+    pub async fn get(
+        pool: &Pool<Postgres>,
+        team_id: &str,
+        slack_user_id: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM betting.slack_users WHERE team_id = $1 AND slack_user_id = $2",
+        )
+        .bind(team_id)
+        .bind(slack_user_id)
+        .fetch_optional(pool)
+        .await
+    }
+    /// Replaces any existing link for the chat user
+    pub async fn link(
+        pool: &Pool<Postgres>,
+        team_id: &str,
+        slack_user_id: &str,
+        user_id: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO betting.slack_users (team_id, slack_user_id, user_id) VALUES ($1, $2, $3) ON CONFLICT (team_id, slack_user_id) DO UPDATE SET user_id = $3",
+        )
+        .bind(team_id)
+        .bind(slack_user_id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        ?;
+        Ok(())
     }
 }
@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{
+    extract::State,
+    response::{Html, Redirect},
+    Form,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csrf::CsrfToken,
+    email::{self, Email, EmailSender},
+    error::{AppError, AppResult},
+    model::{Bet, NewNotification, Notification, User, UserBet, YesOrNoOrNA},
+    repository::Repository,
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+/// A single trade moving the probability by at least this much tells everybody else holding a
+/// position
+pub const SHARP_MOVE_THRESHOLD: f64 = 0.1;
+
+/// How long before a market closes its holders get warned
+const CLOSING_SOON_WINDOW: Duration = Duration::hours(24);
+/// How often to look for markets closing soon
+const CLOSING_SOON_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Everybody with shares in the market, other than `except_user_id`
+fn holders<'a>(user_bets: &'a [UserBet], except_user_id: &str) -> BTreeSet<&'a str> {
+    user_bets
+        .iter()
+        .filter(|user_bet| user_bet.amount > 0 && user_bet.user_id != except_user_id)
+        .map(|user_bet| user_bet.user_id.as_str())
+        .collect()
+}
+
+pub fn market_resolved(
+    bet: &Bet,
+    result: YesOrNoOrNA,
+    user_bets: &[UserBet],
+    payouts: &BTreeMap<String, f64>,
+    actor_id: &str,
+) -> Vec<NewNotification> {
+    holders(user_bets, actor_id)
+        .into_iter()
+        .map(|user_id| NewNotification {
+            user_id: user_id.to_string(),
+            kind: "MarketResolved",
+            bet_id: Some(bet.id.clone()),
+            message: format!(
+                "\"{}\" resolved {result}. You were paid ${:.2}",
+                bet.name,
+                payouts.get(user_id).copied().unwrap_or(0.0)
+            ),
+            // The market page is gone once it's resolved, but its activity isn't
+            link: format!("/activity?bet_id={}", bet.id),
+            dedupe_key: None,
+        })
+        .collect()
+}
+
+pub fn market_closed(bet: &Bet, user_bets: &[UserBet], actor_id: &str) -> Vec<NewNotification> {
+    holders(user_bets, actor_id)
+        .into_iter()
+        .map(|user_id| NewNotification {
+            user_id: user_id.to_string(),
+            kind: "MarketClosed",
+            bet_id: Some(bet.id.clone()),
+            message: format!("\"{}\" was closed to trading", bet.name),
+            link: format!("/bet/{}", bet.id),
+            dedupe_key: None,
+        })
+        .collect()
+}
+
+/// Nothing if the move wasn't big enough to be worth mentioning
+pub fn sharp_move(
+    bet: &Bet,
+    probability_before: f64,
+    user_bets: &[UserBet],
+    trader_id: &str,
+) -> Vec<NewNotification> {
+    let probability_after = bet.probability_of_yes();
+    if (probability_after - probability_before).abs() < SHARP_MOVE_THRESHOLD {
+        return vec![];
+    }
+    holders(user_bets, trader_id)
+        .into_iter()
+        .map(|user_id| NewNotification {
+            user_id: user_id.to_string(),
+            kind: "SharpMove",
+            bet_id: Some(bet.id.clone()),
+            message: format!(
+                "\"{}\" moved from {:.1}% to {:.1}% yes",
+                bet.name,
+                probability_before * 100.0,
+                probability_after * 100.0
+            ),
+            link: format!("/bet/{}", bet.id),
+            dedupe_key: None,
+        })
+        .collect()
+}
+
+pub fn comment_reply(
+    bet: &Bet,
+    comment_id: &str,
+    replier: &User,
+    parent_author_id: &str,
+) -> Vec<NewNotification> {
+    if parent_author_id == replier.id {
+        return vec![];
+    }
+    vec![NewNotification {
+        user_id: parent_author_id.to_string(),
+        kind: "CommentReply",
+        bet_id: Some(bet.id.clone()),
+        message: format!(
+            "{} replied to your comment on \"{}\"",
+            replier.name, bet.name
+        ),
+        link: format!("/bet/{}#comment-{comment_id}", bet.id),
+        dedupe_key: None,
+    }]
+}
+
+/// Warns holders of markets that are about to close. Each holder is only warned once per market
+async fn notify_closing_soon(app_state: &AppState) -> sqlx::Result<()> {
+    let now = Utc::now();
+    let bets = Bet::list_closing_between(&app_state.db, now, now + CLOSING_SOON_WINDOW).await?;
+    if bets.is_empty() {
+        return Ok(());
+    }
+
+    let mut new_notifications = vec![];
+    for bet in bets.iter() {
+        let user_bets = UserBet::list_by_bet_id(&app_state.db, &bet.id).await?;
+        new_notifications.extend(holders(&user_bets, "").into_iter().map(|user_id| {
+            NewNotification {
+                user_id: user_id.to_string(),
+                kind: "ClosingSoon",
+                bet_id: Some(bet.id.clone()),
+                message: format!("\"{}\" closes within a day", bet.name),
+                link: format!("/bet/{}", bet.id),
+                dedupe_key: Some(format!("closing_soon:{}", bet.id)),
+            }
+        }));
+    }
+
+    let mut tx = app_state.db.begin().await?;
+    let notifications = Notification::insert_all(&mut tx, &new_notifications).await?;
+    tx.commit().await?;
+
+    send_emails(app_state, notifications);
+    Ok(())
+}
+
+/// Checks for markets closing soon every so often, starting straight away, until shutdown. On lambda
+/// this only runs while the function is warm, like the webhook dispatcher
+pub fn spawn_closing_soon_checker(app_state: AppState) {
+    let background = app_state.background.clone();
+    let shutdown_started = background.shutdown_started();
+    background.spawn(async move {
+        tokio::pin!(shutdown_started);
+        let mut interval = tokio::time::interval(CLOSING_SOON_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = &mut shutdown_started => break,
+            }
+            if let Err(error) = notify_closing_soon(&app_state).await {
+                log::error!("Couldn't warn about markets closing soon: {error}");
+            }
+        }
+    });
+}
+
+/// Emails everybody who's opted in, in the background so that the request doesn't wait on the mail
+/// server. Call this after the notifications have been committed, since emails can't be taken back.
+/// Failures are only logged, because the inbox still has everything
+pub fn send_emails<R: Repository>(app_state: &AppState<R>, notifications: Vec<Notification>) {
+    let Some(email_sender) = app_state.email_sender.clone() else {
+        return;
+    };
+    if notifications.is_empty() {
+        return;
+    }
+    let db = app_state.db.clone();
+    let site_url = app_state.site_url.clone();
+    app_state.background.spawn(async move {
+        email_notifications(&db, email_sender.as_ref(), &site_url, &notifications).await
+    });
+}
+
+async fn email_notifications<R: Repository>(
+    db: &R,
+    email_sender: &dyn EmailSender,
+    site_url: &str,
+    notifications: &[Notification],
+) {
+    let user_ids = notifications
+        .iter()
+        .map(|notification| notification.user_id.clone())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let users = match db.list_users_by_ids(&user_ids).await {
+        Ok(users) => users,
+        Err(error) => {
+            log::warn!("Couldn't look up who to email notifications to: {error}");
+            return;
+        }
+    };
+    let addresses = users
+        .into_iter()
+        .filter(|user| user.email_notifications)
+        .filter_map(|user| Some((user.id, user.email?)))
+        .collect::<BTreeMap<_, _>>();
+
+    for notification in notifications {
+        let Some(address) = addresses.get(&notification.user_id) else {
+            continue;
+        };
+        let email = Email {
+            to: address.clone(),
+            subject: notification.message.clone(),
+            body: format!(
+                "{}\n\n{site_url}{}\n\nYou can turn these emails off at {site_url}/notifications",
+                notification.message, notification.link,
+            ),
+        };
+        if let Err(error) = email_sender.send(email).await {
+            log::warn!(
+                "Couldn't email notification {} to user {}: {error}",
+                notification.id,
+                notification.user_id
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NotificationInfo {
+    message: String,
+    link: String,
+    created_at_seconds: i64,
+    read: bool,
+}
+
+pub async fn notifications_page(
+    ExtractUserId(user_id): ExtractUserId,
+    csrf_token: CsrfToken,
+    State(app_state): State<AppState>,
+) -> AppResult<Html<String>> {
+    let Some(user) = User::get_by_id(&app_state.db, &user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+
+    let notifications = Notification::list_by_user_id(&app_state.db, &user.id, 100)
+        .await?
+        .into_iter()
+        .map(|notification| NotificationInfo {
+            message: notification.message,
+            link: notification.link,
+            created_at_seconds: notification.created_at.timestamp(),
+            read: notification.read_at.is_some(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut context = tera::Context::new();
+    context.insert("csrf_token", &csrf_token.0);
+    context.insert("notifications", &notifications);
+    context.insert(
+        "has_unread",
+        &notifications.iter().any(|notification| !notification.read),
+    );
+    context.insert("email", &user.email.unwrap_or_default());
+    context.insert("email_notifications", &user.email_notifications);
+    context.insert("email_enabled", &app_state.email_sender.is_some());
+
+    Ok(Html(app_state.engine.render("notifications", &context)?))
+}
+
+pub async fn mark_notifications_read(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+) -> AppResult<Redirect> {
+    Notification::mark_all_read(&app_state.db, &user_id).await?;
+
+    Ok(Redirect::to("/notifications"))
+}
+
+#[derive(Deserialize)]
+pub struct NotificationSettingsRequest {
+    #[serde(default)]
+    email: String,
+    /// Checkboxes aren't sent at all when they're unchecked
+    #[serde(default)]
+    email_notifications: bool,
+}
+pub async fn update_notification_settings(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<NotificationSettingsRequest>,
+) -> AppResult<Redirect> {
+    let email = match request.email.trim() {
+        "" => None,
+        email if email::is_valid_address(email) => Some(email),
+        email => {
+            return Err(AppError::Validation(format!(
+                "\"{email}\" isn't a valid email address"
+            )))
+        }
+    };
+    if request.email_notifications && email.is_none() {
+        return Err(AppError::Validation(
+            "You need an email address to get notifications by email".to_string(),
+        ));
+    }
+
+    User::update_email_settings(&app_state.db, &user_id, email, request.email_notifications)
+        .await?;
+
+    Ok(Redirect::to("/notifications"))
+}
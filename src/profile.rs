@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    response::Html,
+};
+use serde::Serialize;
+
+use crate::{
+    error::{AppError, AppResult},
+    model::{Bet, Event, EventFilter, EventPayload, Payout, User, UserBet, YesOrNo},
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+#[derive(Serialize)]
+struct Position {
+    bet_id: String,
+    bet_name: String,
+    which: YesOrNo,
+    amount: usize,
+    spent: f64,
+    value: f64,
+    unrealized_profit: f64,
+    closed: bool,
+}
+
+#[derive(Serialize)]
+struct CreatedMarket {
+    bet_id: Option<String>,
+    name: String,
+    created_at_seconds: i64,
+    starting_money: usize,
+    /// None once the market has been resolved
+    status: Option<CreatedMarketStatus>,
+}
+#[derive(Serialize)]
+struct CreatedMarketStatus {
+    closed: bool,
+    /// What the liquidity pool would be worth to the creator at the current probability
+    liquidity_value: f64,
+}
+
+#[derive(Serialize)]
+struct RealizedProfit {
+    bet_id: String,
+    bet_name: String,
+    result: String,
+    spent: f64,
+    payout: f64,
+    profit: f64,
+    resolved_at_seconds: i64,
+}
+
+pub async fn user_profile(
+    ExtractUserId(_user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Path(profile_user_id): Path<String>,
+) -> AppResult<Html<String>> {
+    let Some(user) = User::get_by_id(&app_state.db, &profile_user_id).await? else {
+        return Err(AppError::NotFound("No such user".to_string()));
+    };
+
+    let bets = Bet::list(&app_state.db)
+        .await?
+        .into_iter()
+        .map(|bet| (bet.id.clone(), bet))
+        .collect::<BTreeMap<String, Bet>>();
+
+    let mut positions = UserBet::list_by_user_id(&app_state.db, &user.id)
+        .await?
+        .into_iter()
+        // Positions that have been sold out are just history
+        .filter(|user_bet| user_bet.amount > 0)
+        .filter_map(|user_bet| {
+            let bet = bets.get(&user_bet.bet_id)?;
+            let probability = if user_bet.is_yes {
+                bet.probability_of_yes()
+            } else {
+                1.0 - bet.probability_of_yes()
+            };
+            let value = user_bet.amount as f64 * probability;
+            Some(Position {
+                bet_id: bet.id.clone(),
+                bet_name: bet.name.clone(),
+                which: if user_bet.is_yes {
+                    YesOrNo::Yes
+                } else {
+                    YesOrNo::No
+                },
+                amount: user_bet.amount,
+                spent: user_bet.spent,
+                value,
+                unrealized_profit: value - user_bet.spent,
+                closed: bet.is_closed(),
+            })
+        })
+        .collect::<Vec<_>>();
+    positions.sort_by(|a, b| a.bet_name.cmp(&b.bet_name));
+
+    let created_markets = Event::list_filtered(
+        &app_state.db,
+        &EventFilter {
+            actor_id: Some(user.id.clone()),
+            kind: Some("MarketCreated".to_string()),
+            ..Default::default()
+        },
+        None,
+        1000,
+    )
+    .await?
+    .into_iter()
+    .filter_map(|event| match event.payload.0 {
+        EventPayload::MarketCreated {
+            bet_name,
+            starting_money,
+        } => Some(CreatedMarket {
+            status: event
+                .bet_id
+                .as_ref()
+                .and_then(|bet_id| bets.get(bet_id))
+                .map(|bet| {
+                    let probability_of_yes = bet.probability_of_yes();
+                    CreatedMarketStatus {
+                        closed: bet.is_closed(),
+                        liquidity_value: bet.yes_pool * probability_of_yes
+                            + bet.no_pool * (1.0 - probability_of_yes),
+                    }
+                }),
+            bet_id: event.bet_id,
+            name: bet_name,
+            created_at_seconds: event.created_at.timestamp(),
+            starting_money,
+        }),
+        _ => None,
+    })
+    .collect::<Vec<_>>();
+
+    let realized_profits = Payout::list_by_user_id(&app_state.db, &user.id)
+        .await?
+        .into_iter()
+        .map(|payout| RealizedProfit {
+            profit: payout.payout - payout.spent,
+            resolved_at_seconds: payout.resolved_at.timestamp(),
+            bet_id: payout.bet_id,
+            bet_name: payout.bet_name,
+            result: payout.result,
+            spent: payout.spent,
+            payout: payout.payout,
+        })
+        .collect::<Vec<_>>();
+
+    let mut context = tera::Context::new();
+    context.insert(
+        "total_unrealized_profit",
+        &positions
+            .iter()
+            .fold(0.0, |total, position| total + position.unrealized_profit),
+    );
+    context.insert(
+        "total_realized_profit",
+        &realized_profits
+            .iter()
+            .fold(0.0, |total, realized_profit| total + realized_profit.profit),
+    );
+    context.insert("profile_user", &user);
+    context.insert("positions", &positions);
+    context.insert("created_markets", &created_markets);
+    context.insert("realized_profits", &realized_profits);
+
+    Ok(Html(app_state.engine.render("profile", &context)?))
+}
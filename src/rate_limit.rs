@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use envconfig::Envconfig;
+use sqlx::{Pool, Postgres};
+
+use crate::{axum_lambda_util::is_running_on_lambda, jwt::validate_and_extract_user_id};
+
+/// Routes that are limited separately, since they're abused in different ways
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    /// User IDs are all it takes to log in, so this is what stops them being guessed
+    Login,
+    Trade,
+    Create,
+}
+impl RouteGroup {
+    fn of(method: &Method, path: &str) -> Option<Self> {
+        if method != Method::POST {
+            return None;
+        }
+        match path {
+            "/login" => Some(RouteGroup::Login),
+            "/place" => Some(RouteGroup::Trade),
+            "/create" => Some(RouteGroup::Create),
+            _ => None,
+        }
+    }
+}
+impl Display for RouteGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RouteGroup::Login => "login",
+            RouteGroup::Trade => "trade",
+            RouteGroup::Create => "create",
+        })
+    }
+}
+
+#[derive(Envconfig)]
+pub struct RateLimitConfig {
+    /// memory, or postgres so that the limits are shared between lambda instances
+    #[envconfig(from = "RATE_LIMIT_STORE", default = "memory")]
+    pub store: String,
+    #[envconfig(from = "RATE_LIMIT_WINDOW_SECONDS", default = "60")]
+    pub window_seconds: i64,
+    /// Requests allowed per window, for each user and each IP address
+    #[envconfig(from = "RATE_LIMIT_LOGIN", default = "10")]
+    pub login: u32,
+    #[envconfig(from = "RATE_LIMIT_TRADE", default = "60")]
+    pub trade: u32,
+    #[envconfig(from = "RATE_LIMIT_CREATE", default = "10")]
+    pub create: u32,
+    /// Whether the client's IP address is the last one in X-Forwarded-For, which is only true
+    /// behind a proxy that adds it. Defaults to yes on lambda
+    #[envconfig(from = "RATE_LIMIT_TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: Option<bool>,
+}
+
+type CountFuture<'a> = Pin<Box<dyn Future<Output = Result<u32, String>> + Send + 'a>>;
+
+/// Counts requests in fixed windows. Boxed futures rather than an async fn so that it can be used as
+/// a trait object
+pub trait RateLimitStore: Send + Sync {
+    /// Counts a request for `key` in the window starting at `window_start`, returning how many
+    /// there have been in that window so far, including this one
+    fn hit<'a>(&'a self, key: &'a str, window_start: i64) -> CountFuture<'a>;
+}
+
+/// Only limits requests to this instance, which is all there is when not on lambda
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    /// The current window and count for each key
+    counts: Mutex<HashMap<String, (i64, u32)>>,
+}
+/// Past this many keys, the ones from old windows are forgotten
+const MAX_MEMORY_KEYS: usize = 10_000;
+impl RateLimitStore for MemoryRateLimitStore {
+    fn hit<'a>(&'a self, key: &'a str, window_start: i64) -> CountFuture<'a> {
+        Box::pin(async move {
+            let mut counts = self.counts.lock().unwrap();
+            if counts.len() > MAX_MEMORY_KEYS {
+                counts.retain(|_, (window, _)| *window == window_start);
+            }
+            let (window, count) = counts.entry(key.to_string()).or_insert((window_start, 0));
+            if *window != window_start {
+                *window = window_start;
+                *count = 0;
+            }
+            *count += 1;
+            Ok(*count)
+        })
+    }
+}
+
+pub struct PostgresRateLimitStore {
+    pool: Pool<Postgres>,
+}
+impl RateLimitStore for PostgresRateLimitStore {
+    fn hit<'a>(&'a self, key: &'a str, window_start: i64) -> CountFuture<'a> {
+        Box::pin(async move {
+            let describe = |error: sqlx::Error| format!("Database error: {error}");
+            let (count,): (i32,) = sqlx::query_as(
+                "INSERT INTO betting.rate_limits (key, window_start, count) VALUES ($1, $2, 1) ON CONFLICT (key, window_start) DO UPDATE SET count = rate_limits.count + 1 RETURNING count",
+            )
+            .bind(key)
+            .bind(window_start)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(describe)?;
+            // Each window's first request clears out the ones before it
+            if count == 1 {
+                sqlx::query("DELETE FROM betting.rate_limits WHERE window_start < $1")
+                    .bind(window_start)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(describe)?;
+            }
+            Ok(count as u32)
+        })
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    store: Arc<dyn RateLimitStore>,
+    /// For telling who's logged in
+    secret: String,
+}
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, store: Arc<dyn RateLimitStore>, secret: &str) -> Self {
+        Self {
+            config,
+            store,
+            secret: secret.to_string(),
+        }
+    }
+
+    /// Configured from RATE_LIMIT_* environment variables. Errors are messages saying what's
+    /// misconfigured, for the startup logs
+    pub fn from_env(pool: &Pool<Postgres>, secret: &str) -> Result<Self, String> {
+        let config = RateLimitConfig::init_from_env().map_err(|error| error.to_string())?;
+        let store: Arc<dyn RateLimitStore> = match config.store.to_lowercase().as_str() {
+            "memory" => Arc::new(MemoryRateLimitStore::default()),
+            "postgres" => Arc::new(PostgresRateLimitStore { pool: pool.clone() }),
+            other => {
+                return Err(format!(
+                    "Unknown RATE_LIMIT_STORE \"{other}\", expected memory or postgres"
+                ))
+            }
+        };
+        Ok(Self::new(config, store, secret))
+    }
+
+    fn limit(&self, group: RouteGroup) -> u32 {
+        match group {
+            RouteGroup::Login => self.config.login,
+            RouteGroup::Trade => self.config.trade,
+            RouteGroup::Create => self.config.create,
+        }
+    }
+
+    fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        let forwarded_for = request
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        let connected_from = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        match self
+            .config
+            .trust_forwarded_for
+            .unwrap_or(is_running_on_lambda())
+        {
+            true => forwarded_for.or(connected_from),
+            false => connected_from,
+        }
+    }
+
+    /// Counts a request in the group against each of the keys, failing if any of them is over the
+    /// limit
+    async fn hit(&self, group: RouteGroup, keys: &[String]) -> Result<(), RateLimited> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let window_seconds = self.config.window_seconds.max(1);
+        let window_start = now - now % window_seconds;
+        for key in keys {
+            match self.store.hit(key, window_start).await {
+                Ok(count) if count > self.limit(group) => {
+                    let retry_after = (window_start + window_seconds - now).max(1);
+                    log::info!("Rate limited {key} for {retry_after}s");
+                    return Err(RateLimited { retry_after });
+                }
+                Ok(_) => {}
+                // Better to let people trade than to take the site down with the database
+                Err(error) => log::warn!("Couldn't check the rate limit for {key}: {error}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// For requests that don't go through [`limit`], like chat commands. Those all come from the
+    /// chat service's servers, so only the user is limited. They share the limit with the user's
+    /// requests to the site
+    pub async fn hit_user(&self, group: RouteGroup, user_id: &str) -> Result<(), RateLimited> {
+        self.hit(group, &[format!("{group}:user:{user_id}")]).await
+    }
+}
+
+/// A request was turned away for being over the limit
+#[derive(Debug)]
+pub struct RateLimited {
+    /// Seconds until the next window starts
+    pub retry_after: i64,
+}
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Too many requests, try again in {} seconds",
+            self.retry_after
+        )
+    }
+}
+
+/// Turns away requests past the limit for their route group with a 429. Each user and each IP
+/// address gets their own limit, and both have to be under it
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(group) = RouteGroup::of(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    let mut keys = vec![];
+    if let Some(ip) = limiter.client_ip(&request) {
+        keys.push(format!("{group}:ip:{ip}"));
+    }
+    let user_id = jar
+        .get("betting-auth")
+        .and_then(|cookie| validate_and_extract_user_id(cookie.value(), &limiter.secret).ok());
+    if let Some(user_id) = user_id {
+        keys.push(format!("{group}:user:{user_id}"));
+    }
+
+    if let Err(limited) = limiter.hit(group, &keys).await {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, limited.retry_after.to_string())],
+            limited.to_string(),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_routes() {
+        assert_eq!(
+            RouteGroup::of(&Method::POST, "/place"),
+            Some(RouteGroup::Trade)
+        );
+        assert_eq!(
+            RouteGroup::of(&Method::POST, "/login"),
+            Some(RouteGroup::Login)
+        );
+        assert_eq!(RouteGroup::of(&Method::GET, "/login"), None);
+        assert_eq!(RouteGroup::of(&Method::POST, "/comment"), None);
+    }
+
+    #[tokio::test]
+    async fn counts_start_again_each_window() {
+        let store = MemoryRateLimitStore::default();
+        assert_eq!(store.hit("key", 60).await, Ok(1));
+        assert_eq!(store.hit("key", 60).await, Ok(2));
+        assert_eq!(store.hit("other key", 60).await, Ok(1));
+        assert_eq!(store.hit("key", 120).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn users_are_limited_outside_the_middleware_too() {
+        let config = RateLimitConfig {
+            store: "memory".to_string(),
+            window_seconds: 3600,
+            login: 10,
+            trade: 1,
+            create: 10,
+            trust_forwarded_for: None,
+        };
+        let limiter = RateLimiter::new(config, Arc::new(MemoryRateLimitStore::default()), "secret");
+        assert!(limiter.hit_user(RouteGroup::Trade, "bob").await.is_ok());
+        let limited = limiter
+            .hit_user(RouteGroup::Trade, "bob")
+            .await
+            .unwrap_err();
+        assert!(limited.retry_after > 0);
+        assert!(limiter.hit_user(RouteGroup::Create, "bob").await.is_ok());
+        assert!(limiter.hit_user(RouteGroup::Trade, "alice").await.is_ok());
+    }
+}
@@ -0,0 +1,198 @@
+use std::future::Future;
+
+use sqlx::{Pool, Postgres, Transaction};
+
+use crate::{
+    market,
+    model::{Bet, Event, EventPayload, NewNotification, Notification, Payout, User, UserBet},
+};
+
+/// Storage for users, bets, positions and events, so that the trading handlers (placing, creating,
+/// editing, closing and resolving markets, giving out money, and polling for updates) can run
+/// against something other than Postgres.
+///
+/// The read-only pages (the dashboard, leaderboard, profiles and activity) and comments aren't
+/// covered, and query Postgres directly through `AppState`'s default `db`. They lean on full-text
+/// search, comments, seasons and net worth snapshots, which would each need an in-memory copy here
+pub trait Repository: Clone + Send + Sync + 'static {
+    type Transaction: RepositoryTransaction;
+
+    fn begin(&self) -> impl Future<Output = sqlx::Result<Self::Transaction>> + Send;
+    fn list_users_by_ids(
+        &self,
+        ids: &[String],
+    ) -> impl Future<Output = sqlx::Result<Vec<User>>> + Send;
+    fn list_bets(&self) -> impl Future<Output = sqlx::Result<Vec<Bet>>> + Send;
+    /// Up to 100 of the events committed after `after_seq`, oldest first
+    fn list_events_after(
+        &self,
+        after_seq: i64,
+    ) -> impl Future<Output = sqlx::Result<Vec<Event>>> + Send;
+}
+
+/// Everything done through a transaction is thrown away unless it's committed. Anything fetched
+/// "for update" is locked until then
+pub trait RepositoryTransaction: Send {
+    fn commit(self) -> impl Future<Output = sqlx::Result<()>> + Send;
+
+    fn get_user_for_update(
+        &mut self,
+        id: &str,
+    ) -> impl Future<Output = sqlx::Result<Option<User>>> + Send;
+    fn list_users_for_update(&mut self) -> impl Future<Output = sqlx::Result<Vec<User>>> + Send;
+    fn add_money(
+        &mut self,
+        user_id: &str,
+        amount: f64,
+    ) -> impl Future<Output = sqlx::Result<()>> + Send;
+
+    fn get_bet_for_update(
+        &mut self,
+        id: &str,
+    ) -> impl Future<Output = sqlx::Result<Option<Bet>>> + Send;
+    fn insert_bet(&mut self, bet: Bet) -> impl Future<Output = sqlx::Result<()>> + Send;
+    fn update_bet_details(
+        &mut self,
+        id: &str,
+        description: &str,
+        resolution_criteria: &str,
+    ) -> impl Future<Output = sqlx::Result<()>> + Send;
+    fn update_bet_pools(
+        &mut self,
+        id: &str,
+        yes_pool: f64,
+        no_pool: f64,
+    ) -> impl Future<Output = sqlx::Result<()>> + Send;
+    fn close_bet(&mut self, id: &str) -> impl Future<Output = sqlx::Result<()>> + Send;
+    fn delete_bet(&mut self, id: &str) -> impl Future<Output = sqlx::Result<()>> + Send;
+
+    fn get_user_bet_for_update(
+        &mut self,
+        user_id: &str,
+        bet_id: &str,
+        is_yes: bool,
+    ) -> impl Future<Output = sqlx::Result<Option<UserBet>>> + Send;
+    fn list_user_bets_for_update(
+        &mut self,
+        bet_id: &str,
+    ) -> impl Future<Output = sqlx::Result<Vec<UserBet>>> + Send;
+    /// Inserts the position, or replaces it if the user already has one on that side of the bet
+    fn save_user_bet(&mut self, user_bet: UserBet)
+        -> impl Future<Output = sqlx::Result<()>> + Send;
+
+    fn insert_event(
+        &mut self,
+        actor: Option<&User>,
+        bet_id: Option<&str>,
+        payload: EventPayload,
+    ) -> impl Future<Output = sqlx::Result<Event>> + Send;
+    /// Whether anybody has ever bought or sold shares in the bet
+    fn has_trades(&mut self, bet_id: &str) -> impl Future<Output = sqlx::Result<bool>> + Send;
+    fn insert_payout(
+        &mut self,
+        payout: &market::Payout,
+    ) -> impl Future<Output = sqlx::Result<()>> + Send;
+    /// Returns only the notifications that were actually inserted, skipping duplicates
+    fn insert_notifications(
+        &mut self,
+        notifications: &[NewNotification],
+    ) -> impl Future<Output = sqlx::Result<Vec<Notification>>> + Send;
+}
+
+impl Repository for Pool<Postgres> {
+    type Transaction = Transaction<'static, Postgres>;
+
+    async fn begin(&self) -> sqlx::Result<Self::Transaction> {
+        Pool::begin(self).await
+    }
+    async fn list_users_by_ids(&self, ids: &[String]) -> sqlx::Result<Vec<User>> {
+        User::list_by_ids(self, ids).await
+    }
+    async fn list_bets(&self) -> sqlx::Result<Vec<Bet>> {
+        Bet::list(self).await
+    }
+    async fn list_events_after(&self, after_seq: i64) -> sqlx::Result<Vec<Event>> {
+        Event::list_after(self, after_seq).await
+    }
+}
+
+impl RepositoryTransaction for Transaction<'_, Postgres> {
+    async fn commit(self) -> sqlx::Result<()> {
+        Transaction::commit(self).await
+    }
+
+    async fn get_user_for_update(&mut self, id: &str) -> sqlx::Result<Option<User>> {
+        User::get_for_update_by_id(self, id).await
+    }
+    async fn list_users_for_update(&mut self) -> sqlx::Result<Vec<User>> {
+        User::list_for_update(self).await
+    }
+    async fn add_money(&mut self, user_id: &str, amount: f64) -> sqlx::Result<()> {
+        User::add_money(self, user_id, amount).await
+    }
+
+    async fn get_bet_for_update(&mut self, id: &str) -> sqlx::Result<Option<Bet>> {
+        Bet::get_for_update_by_id(self, id).await
+    }
+    async fn insert_bet(&mut self, bet: Bet) -> sqlx::Result<()> {
+        bet.insert(self).await
+    }
+    async fn update_bet_details(
+        &mut self,
+        id: &str,
+        description: &str,
+        resolution_criteria: &str,
+    ) -> sqlx::Result<()> {
+        Bet::update_details(self, id, description, resolution_criteria).await
+    }
+    async fn update_bet_pools(
+        &mut self,
+        id: &str,
+        yes_pool: f64,
+        no_pool: f64,
+    ) -> sqlx::Result<()> {
+        Bet::update_pools(self, id, yes_pool, no_pool).await
+    }
+    async fn close_bet(&mut self, id: &str) -> sqlx::Result<()> {
+        Bet::close(self, id).await
+    }
+    async fn delete_bet(&mut self, id: &str) -> sqlx::Result<()> {
+        Bet::delete(self, id).await
+    }
+
+    async fn get_user_bet_for_update(
+        &mut self,
+        user_id: &str,
+        bet_id: &str,
+        is_yes: bool,
+    ) -> sqlx::Result<Option<UserBet>> {
+        UserBet::get_for_update(self, user_id, bet_id, is_yes).await
+    }
+    async fn list_user_bets_for_update(&mut self, bet_id: &str) -> sqlx::Result<Vec<UserBet>> {
+        UserBet::get_for_update_by_bet_id(self, bet_id).await
+    }
+    async fn save_user_bet(&mut self, user_bet: UserBet) -> sqlx::Result<()> {
+        user_bet.update_or_insert(self).await
+    }
+
+    async fn insert_event(
+        &mut self,
+        actor: Option<&User>,
+        bet_id: Option<&str>,
+        payload: EventPayload,
+    ) -> sqlx::Result<Event> {
+        Event::insert(self, actor, bet_id, payload).await
+    }
+    async fn has_trades(&mut self, bet_id: &str) -> sqlx::Result<bool> {
+        Event::has_trades(self, bet_id).await
+    }
+    async fn insert_payout(&mut self, payout: &market::Payout) -> sqlx::Result<()> {
+        Payout::insert(self, payout).await
+    }
+    async fn insert_notifications(
+        &mut self,
+        notifications: &[NewNotification],
+    ) -> sqlx::Result<Vec<Notification>> {
+        Notification::insert_all(self, notifications).await
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    response::{Html, Redirect},
+    Form,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csrf::CsrfToken,
+    error::{AppError, AppResult},
+    leaderboard::Holdings,
+    live_updates::LiveUpdate,
+    market,
+    model::{
+        Bet, Event, EventPayload, NetWorthSnapshot, Notification, Season, SeasonStanding, User,
+        UserBet, YesOrNoOrNA,
+    },
+    notifications, persist_effects,
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+#[derive(Serialize)]
+struct SeasonInfo {
+    id: i32,
+    name: String,
+    started_at_seconds: i64,
+    ended_at_seconds: Option<i64>,
+    starting_money: Option<f64>,
+}
+impl From<&Season> for SeasonInfo {
+    fn from(season: &Season) -> Self {
+        Self {
+            id: season.id,
+            name: season.name.clone(),
+            started_at_seconds: season.started_at.timestamp(),
+            ended_at_seconds: season.ended_at.map(|ended_at| ended_at.timestamp()),
+            starting_money: season.starting_money,
+        }
+    }
+}
+
+pub async fn seasons(
+    ExtractUserId(user_id): ExtractUserId,
+    csrf_token: CsrfToken,
+    State(app_state): State<AppState>,
+) -> AppResult<Html<String>> {
+    let Some(user) = User::get_by_id(&app_state.db, &user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+
+    let seasons = Season::list(&app_state.db).await?;
+
+    let mut context = tera::Context::new();
+    context.insert("csrf_token", &csrf_token.0);
+    context.insert(
+        "current_season",
+        &seasons
+            .iter()
+            .find(|season| season.ended_at.is_none())
+            .map(SeasonInfo::from),
+    );
+    context.insert(
+        "past_seasons",
+        &seasons
+            .iter()
+            .filter(|season| season.ended_at.is_some())
+            .map(SeasonInfo::from)
+            .collect::<Vec<_>>(),
+    );
+    context.insert("is_admin", &user.is_admin());
+
+    Ok(Html(app_state.engine.render("seasons", &context)?))
+}
+
+pub async fn season_standings(
+    ExtractUserId(_user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Path(season_id): Path<i32>,
+) -> AppResult<Html<String>> {
+    let Some(season) = Season::get_by_id(&app_state.db, season_id).await? else {
+        return Err(AppError::NotFound("No such season".to_string()));
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("season", &SeasonInfo::from(&season));
+    context.insert(
+        "standings",
+        &SeasonStanding::list_by_season_id(&app_state.db, season.id).await?,
+    );
+
+    Ok(Html(app_state.engine.render("season", &context)?))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OpenMarkets {
+    /// Positions in open markets are kept into the new season
+    CarryOver,
+    /// Everybody gets back what they spent, which is then wiped out by the reset anyway
+    ResolveNa,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StartSeasonRequest {
+    name: String,
+    /// What everybody's cash is reset to
+    starting_money: f64,
+    open_markets: OpenMarkets,
+}
+pub async fn start_season(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<StartSeasonRequest>,
+) -> AppResult<Redirect> {
+    if request.name.trim().is_empty() {
+        return Err(AppError::Validation("Seasons need a name".to_string()));
+    }
+    if !(request.starting_money >= 0.0 && request.starting_money.is_finite()) {
+        return Err(AppError::Validation(
+            "Starting money can't be negative".to_string(),
+        ));
+    }
+
+    let mut tx = app_state.db.begin().await?;
+
+    // Only the admin can start seasons, and for anybody else it looks like there's nothing here
+    let users = User::list_for_update(&mut tx).await?;
+    let Some(admin) = users
+        .iter()
+        .find(|user| user.is_admin() && user.id == user_id)
+    else {
+        return Err(AppError::NotFound("Not found".to_string()));
+    };
+
+    let mut bets = Bet::list_for_update(&mut tx).await?;
+    let user_bets = UserBet::list_for_update(&mut tx).await?;
+
+    let now = Utc::now();
+
+    // Everything from before the first season is archived as if it were a season of its own
+    let ending_season = match Season::get_current_for_update(&mut tx).await? {
+        Some(season) => season,
+        None => {
+            let first_event_at = Event::earliest_created_at(&app_state.db)
+                .await?
+                .unwrap_or(now);
+            Season::insert(&mut tx, "Before seasons", first_event_at, None).await?
+        }
+    };
+    Season::end(&mut tx, ending_season.id, now).await?;
+
+    let holdings = Holdings::new(&bets, &user_bets);
+
+    let mut standings = users
+        .iter()
+        .map(|user| SeasonStanding {
+            season_id: ending_season.id,
+            user_id: user.id.clone(),
+            user_name: user.name.clone(),
+            rank: 0,
+            liquid_money: user.money,
+            expected_money: holdings.expected_money(user),
+            max_money: holdings.max_money(user),
+        })
+        .collect::<Vec<_>>();
+    standings.sort_by(|a, b| b.expected_money.total_cmp(&a.expected_money));
+    for (index, standing) in standings.iter_mut().enumerate() {
+        standing.rank = index as i32 + 1;
+    }
+    SeasonStanding::insert_all(&mut tx, &standings).await?;
+
+    let mut events = vec![];
+    let mut notifications = vec![];
+    let mut resolved_bet_ids = vec![];
+    if request.open_markets == OpenMarkets::ResolveNa {
+        for bet in bets.drain(..) {
+            let bet_user_bets = UserBet::get_for_update_by_bet_id(&mut tx, &bet.id).await?;
+            let effects = market::settle(&bet, &bet_user_bets, YesOrNoOrNA::NA);
+            notifications.extend(
+                Notification::insert_all(
+                    &mut tx,
+                    &notifications::market_resolved(
+                        &bet,
+                        YesOrNoOrNA::NA,
+                        &bet_user_bets,
+                        &effects.money,
+                        &admin.id,
+                    ),
+                )
+                .await?,
+            );
+            events.push(persist_effects(&mut tx, admin, effects).await?);
+            resolved_bet_ids.push(bet.id);
+        }
+    }
+
+    User::set_all_money(&mut tx, request.starting_money).await?;
+
+    Season::insert(&mut tx, &request.name, now, Some(request.starting_money)).await?;
+
+    events.push(
+        Event::insert(
+            &mut tx,
+            Some(admin),
+            None,
+            EventPayload::SeasonStarted {
+                season_name: request.name.clone(),
+                starting_money: request.starting_money,
+                resolved_open_markets: request.open_markets == OpenMarkets::ResolveNa,
+            },
+        )
+        .await?,
+    );
+
+    tx.commit().await?;
+
+    // Snapshot everybody's reset net worth, so that season-long changes are measured from here. The
+    // season has already started by now, so this failing only means the leaderboard's charts are off
+    let holdings = Holdings::new(&bets, &user_bets);
+    if let Err(error) = NetWorthSnapshot::insert_all(
+        &app_state.db,
+        now,
+        &users
+            .iter()
+            .map(|user| {
+                let reset_user = User {
+                    id: user.id.clone(),
+                    name: user.name.clone(),
+                    money: request.starting_money,
+                    moderator: user.moderator,
+                    email: None,
+                    email_notifications: false,
+                };
+                (user.id.clone(), holdings.expected_money(&reset_user))
+            })
+            .collect::<BTreeMap<_, _>>(),
+    )
+    .await
+    {
+        log::warn!("Couldn't snapshot net worths for the new season: {error}");
+    }
+
+    for bet_id in resolved_bet_ids {
+        app_state
+            .live_updates
+            .publish(LiveUpdate::BetResolved { bet_id });
+    }
+    for event in events.iter() {
+        app_state.live_updates.publish_event(event);
+    }
+    notifications::send_emails(&app_state, notifications);
+
+    Ok(Redirect::to("/seasons"))
+}
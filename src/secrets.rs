@@ -1,65 +1,317 @@
+use std::{collections::HashMap, future::Future, path::Path, pin::Pin};
+
 use aws_config::{retry::RetryConfig, BehaviorVersion, Region};
 use envconfig::Envconfig;
 use serde::{de::DeserializeOwned, Deserialize};
+use tokio::sync::OnceCell;
 
 use crate::axum_lambda_util::is_running_on_lambda;
 
-async fn get_secret<T>(client: &aws_sdk_secretsmanager::Client, name: &str) -> T
-where
-    T: DeserializeOwned,
-{
-    let req = client
-        .get_secret_value()
-        .set_secret_id(Some(name.to_string()));
-    let resp = req.send().await.unwrap().secret_string.unwrap();
-    serde_json::from_str(&resp).unwrap()
+type SecretFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<String>, String>> + Send + 'a>>;
+
+/// Somewhere secrets can come from. They're looked up by their environment variable names, like
+/// AUTH_SECRET. Boxed futures rather than an async fn so that providers can be chained as trait
+/// objects
+pub trait SecretsProvider: Send + Sync {
+    /// None if this provider doesn't have the secret, so the next one can be tried. Errors are for
+    /// providers that should have it but couldn't get it, which stop the search
+    fn get<'a>(&'a self, name: &'a str) -> SecretFuture<'a>;
 }
 
-#[derive(Envconfig)]
-pub struct Secrets {
-    pub auth_secret: String,
-    pub db_username: String,
-    pub db_password: String,
+/// The process's environment, which also has anything from a .env file
+pub struct EnvSecrets;
+impl SecretsProvider for EnvSecrets {
+    fn get<'a>(&'a self, name: &'a str) -> SecretFuture<'a> {
+        Box::pin(async move { Ok(std::env::var(name).ok()) })
+    }
 }
-impl Secrets {
-    async fn from_lambda_secretsmanager() -> Self {
+
+/// A file in .env format, like a mounted Kubernetes or Docker secret. It's read once, up front
+pub struct FileSecrets {
+    values: HashMap<String, String>,
+}
+impl FileSecrets {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let describe = |error: dotenvy::Error| format!("Couldn't read {}: {error}", path.display());
+        let values = dotenvy::from_path_iter(path)
+            .map_err(describe)?
+            .collect::<Result<_, _>>()
+            .map_err(describe)?;
+        Ok(Self { values })
+    }
+}
+impl SecretsProvider for FileSecrets {
+    fn get<'a>(&'a self, name: &'a str) -> SecretFuture<'a> {
+        Box::pin(async move { Ok(self.values.get(name).cloned()) })
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthSecret {
+    #[serde(rename = "auth-token-signer")]
+    auth_token_signer: String,
+}
+
+#[derive(Deserialize)]
+struct DbSecret {
+    username: String,
+    password: String,
+}
+
+/// AWS Secrets Manager, which is where production's secrets are. Each secret is only fetched the
+/// first time something in it is needed
+pub struct AwsSecrets {
+    client: aws_sdk_secretsmanager::Client,
+    auth_secret_name: String,
+    db_secret_name: String,
+    db_secret: OnceCell<DbSecret>,
+}
+impl AwsSecrets {
+    pub async fn new(region: &str, auth_secret_name: &str, db_secret_name: &str) -> Self {
         let aws_config = aws_config::defaults(BehaviorVersion::latest())
-            .region(Region::new("us-east-1"))
+            .region(Region::new(region.to_string()))
             .retry_config(RetryConfig::disabled())
             .load()
             .await;
 
-        let client = aws_sdk_secretsmanager::Client::new(&aws_config);
-
-        #[derive(Deserialize)]
-        pub struct AuthSecret {
-            #[serde(rename = "auth-token-signer")]
-            pub auth_token_signer: String,
+        Self {
+            client: aws_sdk_secretsmanager::Client::new(&aws_config),
+            auth_secret_name: auth_secret_name.to_string(),
+            db_secret_name: db_secret_name.to_string(),
+            db_secret: OnceCell::new(),
         }
-        let auth_secret = get_secret::<AuthSecret>(&client, "markaronin-auth")
+    }
+
+    async fn get_secret<T>(&self, name: &str) -> Result<T, String>
+    where
+        T: DeserializeOwned,
+    {
+        let req = self
+            .client
+            .get_secret_value()
+            .set_secret_id(Some(name.to_string()));
+        let resp = req
+            .send()
             .await
-            .auth_token_signer;
+            .map_err(|error| format!("Couldn't fetch secret {name}: {error}"))?
+            .secret_string
+            .ok_or_else(|| format!("Secret {name} isn't a string"))?;
+        serde_json::from_str(&resp).map_err(|error| format!("Secret {name} isn't valid: {error}"))
+    }
+
+    async fn db_secret(&self) -> Result<&DbSecret, String> {
+        self.db_secret
+            .get_or_try_init(|| self.get_secret(&self.db_secret_name))
+            .await
+    }
+}
+impl SecretsProvider for AwsSecrets {
+    fn get<'a>(&'a self, name: &'a str) -> SecretFuture<'a> {
+        Box::pin(async move {
+            Ok(Some(match name {
+                "AUTH_SECRET" => {
+                    self.get_secret::<AuthSecret>(&self.auth_secret_name)
+                        .await?
+                        .auth_token_signer
+                }
+                "DB_USERNAME" => self.db_secret().await?.username.clone(),
+                "DB_PASSWORD" => self.db_secret().await?.password.clone(),
+                _ => return Ok(None),
+            }))
+        })
+    }
+}
+
+/// Asks each provider in turn, so earlier ones override later ones
+pub struct ChainedSecrets {
+    providers: Vec<Box<dyn SecretsProvider>>,
+}
+impl SecretsProvider for ChainedSecrets {
+    fn get<'a>(&'a self, name: &'a str) -> SecretFuture<'a> {
+        Box::pin(async move {
+            for provider in &self.providers {
+                if let Some(value) = provider.get(name).await? {
+                    return Ok(Some(value));
+                }
+            }
+            Ok(None)
+        })
+    }
+}
 
-        #[derive(Deserialize)]
-        pub struct DbSecret {
-            pub username: String,
-            pub password: String,
+/// Where to look for secrets
+#[derive(Envconfig)]
+struct SecretsConfig {
+    /// Comma separated, tried in order: any of env, file and aws. Defaults to aws on lambda and env
+    /// everywhere else
+    #[envconfig(from = "SECRETS_PROVIDERS")]
+    providers: Option<String>,
+    /// A .env format file, for the file provider
+    #[envconfig(from = "SECRETS_FILE")]
+    file: Option<String>,
+    #[envconfig(from = "AWS_SECRETS_REGION", default = "us-east-1")]
+    aws_region: String,
+    /// Should have an auth-token-signer key
+    #[envconfig(from = "AWS_AUTH_SECRET_NAME", default = "markaronin-auth")]
+    aws_auth_secret_name: String,
+    /// Should have username and password keys
+    #[envconfig(from = "AWS_DB_SECRET_NAME", default = "betting-db-user")]
+    aws_db_secret_name: String,
+}
+impl SecretsConfig {
+    fn provider_names(&self) -> Vec<&str> {
+        match &self.providers {
+            Some(providers) => providers
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .collect(),
+            None if is_running_on_lambda() => vec!["aws"],
+            None => vec!["env"],
         }
-        let db_secret: DbSecret = get_secret::<DbSecret>(&client, "betting-db-user").await;
+    }
 
-        Self {
-            auth_secret,
-            db_username: db_secret.username,
-            db_password: db_secret.password,
+    async fn providers(&self) -> Result<ChainedSecrets, String> {
+        let mut providers = Vec::<Box<dyn SecretsProvider>>::new();
+        for name in self.provider_names() {
+            providers.push(match name {
+                "env" => Box::new(EnvSecrets),
+                "file" => {
+                    let path = self.file.as_deref().ok_or(
+                        "SECRETS_FILE has to be set to get secrets from a file".to_string(),
+                    )?;
+                    Box::new(FileSecrets::read(Path::new(path))?)
+                }
+                "aws" => Box::new(
+                    AwsSecrets::new(
+                        &self.aws_region,
+                        &self.aws_auth_secret_name,
+                        &self.aws_db_secret_name,
+                    )
+                    .await,
+                ),
+                other => {
+                    return Err(format!(
+                        "Unknown secrets provider \"{other}\", expected env, file or aws"
+                    ))
+                }
+            });
         }
+        Ok(ChainedSecrets { providers })
+    }
+}
+
+pub struct Secrets {
+    pub auth_secret: String,
+    /// Not needed when DATABASE_URL has them
+    pub db_username: Option<String>,
+    pub db_password: Option<String>,
+    /// Only needed if the SMTP server wants a login
+    pub smtp_password: Option<String>,
+    /// Chat commands are turned off if this isn't set
+    pub slack_signing_secret: Option<String>,
+}
+impl Secrets {
+    pub async fn from_provider(provider: &dyn SecretsProvider) -> Result<Self, String> {
+        Ok(Self {
+            auth_secret: provider
+                .get("AUTH_SECRET")
+                .await?
+                .ok_or("AUTH_SECRET isn't set".to_string())?,
+            db_username: provider.get("DB_USERNAME").await?,
+            db_password: provider.get("DB_PASSWORD").await?,
+            smtp_password: provider.get("SMTP_PASSWORD").await?,
+            slack_signing_secret: provider.get("SLACK_SIGNING_SECRET").await?,
+        })
     }
 
-    pub async fn load() -> Self {
-        if is_running_on_lambda() {
-            Self::from_lambda_secretsmanager().await
-        } else {
-            dotenvy::dotenv().unwrap();
-            Self::init_from_env().unwrap()
+    /// Errors are messages saying what's missing, for the startup logs
+    pub async fn load() -> Result<Self, String> {
+        if !is_running_on_lambda() {
+            // A .env file is optional, since everything can also come from the real environment
+            if let Err(error) = dotenvy::dotenv() {
+                if !error.not_found() {
+                    return Err(format!("Couldn't read .env: {error}"));
+                }
+            }
         }
+        let config = SecretsConfig::init_from_env().map_err(|error| error.to_string())?;
+        Self::from_provider(&config.providers().await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeSecrets(Result<HashMap<&'static str, &'static str>, String>);
+    impl SecretsProvider for FakeSecrets {
+        fn get<'a>(&'a self, name: &'a str) -> SecretFuture<'a> {
+            Box::pin(async move {
+                match &self.0 {
+                    Ok(values) => Ok(values.get(name).map(|value| value.to_string())),
+                    Err(error) => Err(error.clone()),
+                }
+            })
+        }
+    }
+
+    fn chain(providers: Vec<FakeSecrets>) -> ChainedSecrets {
+        ChainedSecrets {
+            providers: providers
+                .into_iter()
+                .map(|provider| Box::new(provider) as Box<dyn SecretsProvider>)
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn earlier_providers_win_and_later_ones_fill_in() {
+        let secrets = chain(vec![
+            FakeSecrets(Ok(HashMap::from([("AUTH_SECRET", "from the first")]))),
+            FakeSecrets(Ok(HashMap::from([
+                ("AUTH_SECRET", "from the second"),
+                ("DB_USERNAME", "betting"),
+            ]))),
+        ]);
+
+        let secrets = Secrets::from_provider(&secrets).await.unwrap();
+        assert_eq!(secrets.auth_secret, "from the first");
+        assert_eq!(secrets.db_username.as_deref(), Some("betting"));
+        assert_eq!(secrets.db_password, None);
+    }
+
+    #[tokio::test]
+    async fn errors_and_missing_secrets_stop_startup() {
+        let secrets = chain(vec![FakeSecrets(Ok(HashMap::new()))]);
+        assert!(Secrets::from_provider(&secrets).await.is_err());
+
+        // An outage shouldn't quietly fall back to something else
+        let secrets = chain(vec![
+            FakeSecrets(Err("Couldn't fetch secret".to_string())),
+            FakeSecrets(Ok(HashMap::from([("AUTH_SECRET", "fallback")]))),
+        ]);
+        assert_eq!(
+            Secrets::from_provider(&secrets).await.err().unwrap(),
+            "Couldn't fetch secret"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_secrets_files() {
+        let path = std::env::temp_dir().join(format!("betting-secrets-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "AUTH_SECRET=\"from a file\"\nDB_PASSWORD=hunter2\nSLACK_SIGNING_SECRET=shh\n",
+        )
+        .unwrap();
+        let secrets = FileSecrets::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let secrets = Secrets::from_provider(&secrets.unwrap()).await.unwrap();
+        assert_eq!(secrets.auth_secret, "from a file");
+        assert_eq!(secrets.db_password.as_deref(), Some("hunter2"));
+        assert_eq!(secrets.slack_signing_secret.as_deref(), Some("shh"));
+        assert!(FileSecrets::read(&path).is_err());
     }
 }
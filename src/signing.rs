@@ -0,0 +1,41 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Hex HMAC-SHA256 of `message`
+pub fn sign(secret: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Checks a hex HMAC-SHA256 in constant time, so that it can't be guessed a byte at a time
+pub fn verify(secret: &str, message: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(message.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn verifies_only_matching_signatures() {
+        let signature = sign("key", "message");
+        assert!(verify("key", "message", &signature));
+        assert!(!verify("key", "messages", &signature));
+        assert!(!verify("other key", "message", &signature));
+        assert!(!verify("key", "message", "not hex"));
+    }
+}
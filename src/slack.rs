@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use chrono::Utc;
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    csrf::CsrfToken,
+    error::{AppError, AppResult},
+    execute_create_bet, execute_place_bet,
+    model::{Bet, BetSearch, SlackUser, User, UserBet, YesOrNo},
+    rate_limit::RouteGroup,
+    signing,
+    user_id_cookie::ExtractUserId,
+    AppState, CreateBetRequest, PlaceBetRequest,
+};
+
+/// Requests older than this are rejected, so that captured ones can't be replayed
+const MAX_REQUEST_AGE_SECONDS: i64 = 5 * 60;
+/// How long a `/bet link` link works for
+const LINK_LIFETIME_SECONDS: i64 = 60 * 60;
+const MAX_LISTED_MARKETS: usize = 20;
+
+const USAGE: &str = "Usage:
+`/bet list` - open markets
+`/bet show <market>` - a market and your position in it
+`/bet buy <market> yes|no <shares>` - buy shares
+`/bet create \"question\" <starting money>` - create a market
+`/bet link` - connect your chat account to your betting account
+Markets can be given by the start of their ID or part of their name, in quotes if it has spaces";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    Link,
+    List,
+    Show {
+        market: String,
+    },
+    Buy {
+        market: String,
+        which: YesOrNo,
+        amount: usize,
+    },
+    Create {
+        question: String,
+        starting_money: usize,
+    },
+}
+
+/// Splits on whitespace, except inside quotes. Chat clients like to turn straight quotes into curly
+/// ones, so those count too
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' | '“' | '”' => {
+                if quoted {
+                    tokens.push(current.take().unwrap_or_default());
+                } else if let Some(token) = current.take() {
+                    tokens.push(token);
+                }
+                quoted = !quoted;
+                if quoted {
+                    current = Some(String::new());
+                }
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(token) = current.take() {
+                    tokens.push(token);
+                }
+            }
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("There's an unclosed quote".to_string());
+    }
+    tokens.extend(current);
+    Ok(tokens)
+}
+
+fn parse_command(text: &str) -> Result<Command, String> {
+    let tokens = tokenize(text)?;
+    let tokens = tokens.iter().map(String::as_str).collect::<Vec<_>>();
+    let parse_number = |number: &str| {
+        number
+            .trim_start_matches('$')
+            .parse::<usize>()
+            .map_err(|_| format!("\"{number}\" isn't a whole number"))
+    };
+    match tokens.as_slice() {
+        [] | ["help"] => Ok(Command::Help),
+        ["link"] => Ok(Command::Link),
+        ["list"] => Ok(Command::List),
+        ["show", market] => Ok(Command::Show {
+            market: market.to_string(),
+        }),
+        ["buy", market, which, amount] => Ok(Command::Buy {
+            market: market.to_string(),
+            which: match which.to_lowercase().as_str() {
+                "yes" => YesOrNo::Yes,
+                "no" => YesOrNo::No,
+                _ => return Err(format!("\"{which}\" should be yes or no")),
+            },
+            amount: parse_number(amount)?,
+        }),
+        ["create", question, starting_money] => Ok(Command::Create {
+            question: question.to_string(),
+            starting_money: parse_number(starting_money)?,
+        }),
+        _ => Err(format!("I didn't understand that.\n{USAGE}")),
+    }
+}
+
+/// Slack signs `v0:<timestamp>:<body>` with the app's signing secret
+fn verify_signature(
+    signing_secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+    now_seconds: i64,
+) -> bool {
+    let Ok(timestamp_seconds) = timestamp.parse::<i64>() else {
+        return false;
+    };
+    if (now_seconds - timestamp_seconds).abs() > MAX_REQUEST_AGE_SECONDS {
+        return false;
+    }
+    let Ok(body) = std::str::from_utf8(body) else {
+        return false;
+    };
+    let Some(signature) = signature.strip_prefix("v0=") else {
+        return false;
+    };
+    signing::verify(signing_secret, &format!("v0:{timestamp}:{body}"), signature)
+}
+
+/// What's signed to make a `/bet link` link
+fn link_message(team_id: &str, slack_user_id: &str, expires: i64) -> String {
+    format!("slack-link:{team_id}:{slack_user_id}:{expires}")
+}
+
+/// Chat messages treat these as markup, so user-written text has to have them escaped
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn market_link(site_url: &str, bet: &Bet) -> String {
+    format!("<{site_url}/bet/{}|{}>", bet.id, escape(&bet.name))
+}
+
+/// The first 8 characters of the ID, which is plenty to tell markets apart
+fn short_id(bet: &Bet) -> &str {
+    &bet.id[..8]
+}
+
+/// Finds a market by (the start of) its ID or part of its name
+async fn find_market(app_state: &AppState, reference: &str) -> AppResult<Bet> {
+    if let Some(bet) = Bet::get_by_id(&app_state.db, reference).await? {
+        return Ok(bet);
+    }
+    let lowercase_reference = reference.to_lowercase();
+    let mut matches = Bet::list(&app_state.db)
+        .await?
+        .into_iter()
+        .filter(|bet| {
+            (reference.len() >= 4 && bet.id.starts_with(reference))
+                || bet.name.to_lowercase().contains(&lowercase_reference)
+        })
+        .collect::<Vec<_>>();
+    match matches.len() {
+        0 => Err(AppError::NotFound(format!(
+            "No market matches \"{reference}\""
+        ))),
+        1 => Ok(matches.remove(0)),
+        count => Err(AppError::Validation(format!(
+            "\"{reference}\" matches {count} markets, which did you mean?\n{}",
+            matches
+                .iter()
+                .take(5)
+                .map(|bet| format!("`{}` {}", short_id(bet), escape(&bet.name)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ))),
+    }
+}
+
+/// Only the person who ran the command sees the reply
+fn private_reply(text: impl Into<String>) -> Response {
+    Json(json!({ "response_type": "ephemeral", "text": text.into() })).into_response()
+}
+/// Chat commands have to answer with a 200 for the user to see anything, so errors become private
+/// replies rather than error responses
+fn error_reply(error: AppError) -> Response {
+    error.log();
+    private_reply(error.to_string())
+}
+/// Everybody in the channel sees the reply
+fn public_reply(text: impl Into<String>) -> Response {
+    Json(json!({ "response_type": "in_channel", "text": text.into() })).into_response()
+}
+
+async fn run_command(
+    app_state: &AppState,
+    command: Command,
+    team_id: &str,
+    slack_user_id: &str,
+) -> AppResult<Response> {
+    let user = match SlackUser::get(&app_state.db, team_id, slack_user_id).await? {
+        Some(slack_user) => User::get_by_id(&app_state.db, &slack_user.user_id).await?,
+        None => None,
+    };
+    let needs_link = || {
+        Ok(private_reply(
+            "Run `/bet link` first to connect your betting account",
+        ))
+    };
+
+    Ok(match command {
+        Command::Help => private_reply(USAGE),
+        Command::Link => {
+            let expires = Utc::now().timestamp() + LINK_LIFETIME_SECONDS;
+            let signature = signing::sign(
+                &app_state.secret,
+                &link_message(team_id, slack_user_id, expires),
+            );
+            private_reply(format!(
+                "<{}/slack/link?team_id={}&slack_user_id={}&expires={expires}&signature={signature}|Click here> within the next hour to connect your betting account",
+                app_state.site_url,
+                url::form_urlencoded::byte_serialize(team_id.as_bytes()).collect::<String>(),
+                url::form_urlencoded::byte_serialize(slack_user_id.as_bytes()).collect::<String>(),
+            ))
+        }
+        Command::List => {
+            let open_bets = Bet::search(&app_state.db, &BetSearch::default())
+                .await?
+                .into_iter()
+                .filter(|bet| !bet.is_closed())
+                .collect::<Vec<_>>();
+            if open_bets.is_empty() {
+                return Ok(private_reply("There aren't any open markets"));
+            }
+            let mut lines = open_bets
+                .iter()
+                .take(MAX_LISTED_MARKETS)
+                .map(|bet| {
+                    format!(
+                        "`{}` {} - {:.1}% yes",
+                        short_id(bet),
+                        market_link(&app_state.site_url, bet),
+                        bet.probability_of_yes() * 100.0
+                    )
+                })
+                .collect::<Vec<_>>();
+            if open_bets.len() > MAX_LISTED_MARKETS {
+                lines.push(format!(
+                    "...and {} more on <{}|the site>",
+                    open_bets.len() - MAX_LISTED_MARKETS,
+                    app_state.site_url
+                ));
+            }
+            private_reply(lines.join("\n"))
+        }
+        Command::Show { market } => {
+            let bet = find_market(app_state, &market).await?;
+            let mut lines = vec![
+                format!(
+                    "*{}* (`{}`)",
+                    market_link(&app_state.site_url, &bet),
+                    short_id(&bet)
+                ),
+                format!(
+                    "{:.1}% yes{}",
+                    bet.probability_of_yes() * 100.0,
+                    if bet.is_closed() { " - closed" } else { "" }
+                ),
+            ];
+            if let Some(closes_at) = bet.closes_at {
+                lines.push(format!(
+                    "Closes at {}",
+                    closes_at.format("%H:%M %Y-%m-%d UTC")
+                ));
+            }
+            if !bet.resolution_criteria.is_empty() {
+                lines.push(format!("Resolves: {}", escape(&bet.resolution_criteria)));
+            }
+            if let Some(user) = user {
+                for user_bet in UserBet::list_by_user_id(&app_state.db, &user.id)
+                    .await?
+                    .iter()
+                    .filter(|user_bet| user_bet.bet_id == bet.id && user_bet.amount > 0)
+                {
+                    lines.push(format!(
+                        "You hold {} {} shares, bought for ${:.2}",
+                        user_bet.amount,
+                        if user_bet.is_yes { "yes" } else { "no" },
+                        user_bet.spent
+                    ));
+                }
+            }
+            private_reply(lines.join("\n"))
+        }
+        Command::Buy {
+            market,
+            which,
+            amount,
+        } => {
+            let Some(user) = user else {
+                return needs_link();
+            };
+            if let Err(limited) = app_state
+                .rate_limiter
+                .hit_user(RouteGroup::Trade, &user.id)
+                .await
+            {
+                return Ok(private_reply(limited.to_string()));
+            }
+            let bet = find_market(app_state, &market).await?;
+            let request = PlaceBetRequest {
+                bet_id: bet.id,
+                amount,
+                which,
+                // There's no page that could have gone stale, so whatever the current price is
+                max_cost: String::new(),
+            };
+            let (bet, spent) = execute_place_bet(app_state, &user.id, &request).await?;
+            public_reply(format!(
+                "{} bought {amount} {which} shares in {} for ${spent:.2}, moving it to {:.1}% yes",
+                escape(&user.name),
+                market_link(&app_state.site_url, &bet),
+                bet.probability_of_yes() * 100.0
+            ))
+        }
+        Command::Create {
+            question,
+            starting_money,
+        } => {
+            let Some(user) = user else {
+                return needs_link();
+            };
+            if let Err(limited) = app_state
+                .rate_limiter
+                .hit_user(RouteGroup::Create, &user.id)
+                .await
+            {
+                return Ok(private_reply(limited.to_string()));
+            }
+            let request = CreateBetRequest {
+                name: question,
+                description: String::new(),
+                resolution_criteria: String::new(),
+                tags: String::new(),
+                closes_at: String::new(),
+                starting_money,
+            };
+            let bet = execute_create_bet(app_state, &user.id, request).await?;
+            public_reply(format!(
+                "{} created {} (`{}`) with ${starting_money}",
+                escape(&user.name),
+                market_link(&app_state.site_url, &bet),
+                short_id(&bet)
+            ))
+        }
+    })
+}
+
+/// Slash command endpoint. Everything comes back as a 200 with a message, since that's the only way
+/// to show the user anything
+pub async fn slash_command(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(signing_secret) = &app_state.slack_signing_secret else {
+        return AppError::NotFound("Chat commands aren't set up".to_string()).into_response();
+    };
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    if !verify_signature(
+        signing_secret,
+        header("X-Slack-Request-Timestamp"),
+        &body,
+        header("X-Slack-Signature"),
+        Utc::now().timestamp(),
+    ) {
+        return AppError::Unauthorized("Invalid request signature".to_string()).into_response();
+    }
+
+    let fields = url::form_urlencoded::parse(&body)
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let field = |name: &str| fields.get(name).map(String::as_str).unwrap_or_default();
+
+    match parse_command(field("text")) {
+        Ok(command) => run_command(&app_state, command, field("team_id"), field("user_id"))
+            .await
+            .unwrap_or_else(error_reply),
+        Err(message) => private_reply(message),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct LinkRequest {
+    team_id: String,
+    slack_user_id: String,
+    expires: i64,
+    signature: String,
+}
+impl LinkRequest {
+    fn is_valid(&self, secret: &str) -> bool {
+        self.expires > Utc::now().timestamp()
+            && signing::verify(
+                secret,
+                &link_message(&self.team_id, &self.slack_user_id, self.expires),
+                &self.signature,
+            )
+    }
+}
+
+/// Where `/bet link` sends people. Confirms before linking, so that following a link somebody else
+/// generated doesn't silently hand over your account
+pub async fn link_page(
+    ExtractUserId(user_id): ExtractUserId,
+    csrf_token: CsrfToken,
+    State(app_state): State<AppState>,
+    Query(request): Query<LinkRequest>,
+) -> AppResult<Html<String>> {
+    if !request.is_valid(&app_state.secret) {
+        return Err(AppError::Validation(
+            "This link has expired, run /bet link again".to_string(),
+        ));
+    }
+    let Some(user) = User::get_by_id(&app_state.db, &user_id).await? else {
+        return Err(AppError::user_not_found());
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("csrf_token", &csrf_token.0);
+    context.insert("user", &user);
+    context.insert("link", &request);
+
+    Ok(Html(app_state.engine.render("slack_link", &context)?))
+}
+
+pub async fn link(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<LinkRequest>,
+) -> AppResult<Redirect> {
+    if !request.is_valid(&app_state.secret) {
+        return Err(AppError::Validation(
+            "This link has expired, run /bet link again".to_string(),
+        ));
+    }
+
+    SlackUser::link(
+        &app_state.db,
+        &request.team_id,
+        &request.slack_user_id,
+        &user_id,
+    )
+    .await?;
+
+    Ok(Redirect::to("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command(""), Ok(Command::Help));
+        assert_eq!(parse_command("list"), Ok(Command::List));
+        assert_eq!(
+            parse_command("show \"will it rain\""),
+            Ok(Command::Show {
+                market: "will it rain".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command("buy 1a2b3c4d YES 10"),
+            Ok(Command::Buy {
+                market: "1a2b3c4d".to_string(),
+                which: YesOrNo::Yes,
+                amount: 10
+            })
+        );
+        assert_eq!(
+            parse_command("create “Will it snow tomorrow?” $50"),
+            Ok(Command::Create {
+                question: "Will it snow tomorrow?".to_string(),
+                starting_money: 50
+            })
+        );
+        assert!(parse_command("buy rain maybe 10").is_err());
+        assert!(parse_command("buy rain yes ten").is_err());
+        assert!(parse_command("create \"unclosed 50").is_err());
+    }
+
+    #[test]
+    fn verifies_slack_signatures() {
+        let body = b"token=x&team_id=T1&user_id=U1&command=%2Fbet&text=list";
+        let signature = format!(
+            "v0={}",
+            signing::sign(
+                "secret",
+                &format!("v0:1000:{}", std::str::from_utf8(body).unwrap())
+            )
+        );
+        assert!(verify_signature("secret", "1000", body, &signature, 1000));
+        assert!(verify_signature("secret", "1000", body, &signature, 1200));
+        // Too old
+        assert!(!verify_signature("secret", "1000", body, &signature, 2000));
+        assert!(!verify_signature("wrong", "1000", body, &signature, 1000));
+        assert!(!verify_signature("secret", "1001", body, &signature, 1000));
+    }
+}
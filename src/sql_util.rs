@@ -1,25 +1,87 @@
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use url::Url;
+use std::str::FromStr;
 
-pub async fn get_db_connection_pool(
-    db_username: &str,
-    db_password: &str,
-) -> Result<Pool<Postgres>, sqlx::Error> {
-    let url_encoded_password: String =
-        url::form_urlencoded::byte_serialize(db_password.as_bytes()).collect();
+use envconfig::Envconfig;
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    postgres::{PgConnectOptions, PgPoolOptions, PgSslMode},
+    Pool, Postgres,
+};
+
+use crate::axum_lambda_util::is_running_on_lambda;
 
-    let domain = "terraform-20230820160825878800000001.cxp0he9jcakq.us-east-1.rds.amazonaws.com";
-    let port = 5432;
-    let db_name = "markaronindb";
+/// Everything in /migrations, embedded in the binary
+static MIGRATOR: Migrator = sqlx::migrate!();
 
-    let connection_string = format!("postgres://{domain}:{port}/{db_name}");
+/// Where the database is. Defaults to a local one, and deploy.bash sets production's on the lambda
+#[derive(Envconfig)]
+pub struct DbConfig {
+    /// A full postgres:// URL, including credentials. Everything else here is ignored if it's set
+    #[envconfig(from = "DATABASE_URL")]
+    pub url: Option<String>,
+    #[envconfig(from = "DB_HOST", default = "localhost")]
+    pub host: String,
+    #[envconfig(from = "DB_PORT", default = "5432")]
+    pub port: u16,
+    #[envconfig(from = "DB_NAME", default = "markaronindb")]
+    pub name: String,
+    /// One of disable, allow, prefer, require, verify-ca or verify-full
+    #[envconfig(from = "DB_SSL_MODE", default = "prefer")]
+    pub ssl_mode: String,
+    #[envconfig(from = "DB_MAX_CONNECTIONS", default = "5")]
+    pub max_connections: u32,
+    /// Whether to migrate the database on startup. Defaults to yes, except on lambda where the app's
+    /// database user can't create tables - run `betting migrate` as a user that can instead
+    #[envconfig(from = "RUN_MIGRATIONS")]
+    pub run_migrations: Option<bool>,
+}
+impl DbConfig {
+    pub fn should_run_migrations(&self) -> bool {
+        self.run_migrations.unwrap_or(!is_running_on_lambda())
+    }
+
+    /// The username and password come from secrets unless they're in DATABASE_URL
+    fn connect_options(
+        &self,
+        db_username: Option<&str>,
+        db_password: Option<&str>,
+    ) -> Result<PgConnectOptions, sqlx::Error> {
+        if let Some(url) = &self.url {
+            return PgConnectOptions::from_str(url);
+        }
 
-    let mut url = Url::parse(&connection_string).unwrap();
-    url.set_username(db_username).unwrap();
-    url.set_password(Some(&url_encoded_password)).unwrap();
+        let mut options = PgConnectOptions::new()
+            .host(&self.host)
+            .port(self.port)
+            .database(&self.name)
+            .ssl_mode(PgSslMode::from_str(&self.ssl_mode)?);
+        if let Some(db_username) = db_username {
+            options = options.username(db_username);
+        }
+        if let Some(db_password) = db_password {
+            options = options.password(db_password);
+        }
+        Ok(options)
+    }
+}
 
+pub async fn get_db_connection_pool(
+    config: &DbConfig,
+    db_username: Option<&str>,
+    db_password: Option<&str>,
+) -> Result<Pool<Postgres>, sqlx::Error> {
     PgPoolOptions::new()
-        .max_connections(5)
-        .connect(url.as_str())
+        .max_connections(config.max_connections)
+        .connect_with(config.connect_options(db_username, db_password)?)
         .await
 }
+
+/// Applies any migrations the database doesn't have yet
+pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<(), MigrateError> {
+    MIGRATOR.run(pool).await
+}
+
+/// Checks that a connection can be made and used, for health checks
+pub async fn ping(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+    Ok(())
+}
@@ -1,17 +1,20 @@
 use axum::{async_trait, extract::FromRequestParts, http::request::Parts, response::Redirect};
-use axum_extra::extract::CookieJar;
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
 
-use crate::{jwt::validate_and_extract_user_id, AppState};
+use crate::{jwt::validate_and_extract_user_id, repository::Repository, AppState};
 
 pub struct ExtractUserId(pub String);
 
 #[async_trait]
-impl FromRequestParts<AppState> for ExtractUserId {
+impl<R: Repository> FromRequestParts<AppState<R>> for ExtractUserId {
     type Rejection = Redirect;
 
     async fn from_request_parts(
         parts: &mut Parts,
-        state: &AppState,
+        state: &AppState<R>,
     ) -> Result<Self, Self::Rejection> {
         let jar = CookieJar::from_request_parts(parts, state).await.unwrap();
 
@@ -29,3 +32,13 @@ impl FromRequestParts<AppState> for ExtractUserId {
         }
     }
 }
+
+/// Scripts can't read it, other sites' forms don't send it, and it's HTTPS only if the site is
+pub fn hardened_cookie(name: &'static str, value: String, site_url: &str) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(site_url.starts_with("https://"))
+        .build()
+}
@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    response::{Html, Redirect},
+};
+use axum_extra::extract::Form;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use url::Url;
+
+use crate::{
+    background::Background,
+    csrf::CsrfToken,
+    error::{AppError, AppResult},
+    live_updates::LiveUpdates,
+    model::{User, Webhook, WebhookDelivery},
+    signing,
+    user_id_cookie::ExtractUserId,
+    AppState,
+};
+
+/// Failed deliveries are retried after 30s, 1m, 2m, ... until they've been tried this many times
+const MAX_ATTEMPTS: i32 = 8;
+const FIRST_RETRY_DELAY: chrono::Duration = chrono::Duration::seconds(30);
+
+/// Nothing else will try a delivery for this long after it's been picked up
+const LEASE: chrono::Duration = chrono::Duration::minutes(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to check for retries that have come due when nothing else is happening
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Delay before the next attempt, after `attempts` failures
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    FIRST_RETRY_DELAY * 2_i32.pow((attempts - 1).clamp(0, 16) as u32)
+}
+
+/// Sends every delivery that's due. Returns how many were attempted
+async fn deliver_due(pool: &Pool<Postgres>, client: &reqwest::Client) -> sqlx::Result<usize> {
+    let deliveries = WebhookDelivery::claim_due(pool, LEASE, 20).await?;
+
+    for delivery in deliveries.iter() {
+        let body = serde_json::to_string(&delivery.body.0).unwrap();
+        let timestamp = Utc::now().timestamp();
+        let signature = signing::sign(
+            delivery.secret.as_deref().unwrap_or_default(),
+            &format!("{timestamp}.{body}"),
+        );
+
+        let result = client
+            .post(delivery.url.as_deref().unwrap_or_default())
+            .timeout(REQUEST_TIMEOUT)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(
+                "X-Betting-Event",
+                delivery.body.0["kind"].as_str().unwrap_or_default(),
+            )
+            .header("X-Betting-Delivery", &delivery.id)
+            .header("X-Betting-Timestamp", timestamp.to_string())
+            .header("X-Betting-Signature", format!("sha256={signature}"))
+            .body(body)
+            .send()
+            .await;
+
+        let (status, error) = match result {
+            Ok(response) if response.status().is_success() => (Some(response.status()), None),
+            Ok(response) => (
+                Some(response.status()),
+                Some(format!("Responded with {}", response.status())),
+            ),
+            Err(error) => (None, Some(error.to_string())),
+        };
+
+        let attempts = delivery.attempts + 1;
+        let next_attempt_at = match &error {
+            Some(error) if attempts < MAX_ATTEMPTS => {
+                log::info!(
+                    "Webhook delivery {} failed (attempt {attempts}), retrying: {error}",
+                    delivery.id
+                );
+                Some(Utc::now() + retry_delay(attempts))
+            }
+            Some(error) => {
+                log::warn!(
+                    "Webhook delivery {} failed {attempts} times, giving up: {error}",
+                    delivery.id
+                );
+                None
+            }
+            None => None,
+        };
+
+        WebhookDelivery::record_attempt(
+            pool,
+            &delivery.id,
+            error.is_none(),
+            status.map(|status| status.as_u16() as i32),
+            error.as_deref(),
+            next_attempt_at,
+        )
+        .await?;
+    }
+
+    Ok(deliveries.len())
+}
+
+/// Sends webhooks in the background until shutdown. Deliveries are queued in the database alongside
+/// their events, so this wakes up whenever an event is published, and otherwise checks every so often
+/// for retries. On lambda this only runs while the function is warm, so retries can come late but
+/// aren't lost
+pub fn spawn_dispatcher(pool: Pool<Postgres>, live_updates: &LiveUpdates, background: &Background) {
+    let mut updates = live_updates.subscribe();
+    let shutdown_started = background.shutdown_started();
+    background.spawn(async move {
+        tokio::pin!(shutdown_started);
+        let client = reqwest::Client::new();
+        loop {
+            // Keep going while there's a backlog. Anything that fails is picked up again once its
+            // lease runs out
+            loop {
+                match deliver_due(&pool, &client).await {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(error) => {
+                        log::error!("Couldn't deliver webhooks: {error}");
+                        break;
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = updates.recv() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = &mut shutdown_started => break,
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct WebhookInfo {
+    id: String,
+    url: String,
+    event_kinds: Vec<String>,
+    created_at_seconds: i64,
+}
+
+#[derive(Serialize)]
+struct DeliveryInfo {
+    url: String,
+    kind: String,
+    description: String,
+    created_at_seconds: i64,
+    attempts: i32,
+    delivered: bool,
+    pending: bool,
+    last_status: Option<i32>,
+    last_error: Option<String>,
+}
+
+/// Webhooks are admin-only, and for anybody else it looks like there's nothing here
+async fn require_admin(app_state: &AppState, user_id: &str) -> AppResult<()> {
+    match User::get_by_id(&app_state.db, user_id).await? {
+        Some(user) if user.is_admin() => Ok(()),
+        _ => Err(AppError::NotFound("Not found".to_string())),
+    }
+}
+
+pub async fn webhooks_page(
+    ExtractUserId(user_id): ExtractUserId,
+    csrf_token: CsrfToken,
+    State(app_state): State<AppState>,
+) -> AppResult<Html<String>> {
+    require_admin(&app_state, &user_id).await?;
+
+    let webhooks = Webhook::list(&app_state.db)
+        .await?
+        .into_iter()
+        .map(|webhook| WebhookInfo {
+            id: webhook.id,
+            url: webhook.url,
+            event_kinds: webhook.event_kinds,
+            created_at_seconds: webhook.created_at.timestamp(),
+        })
+        .collect::<Vec<_>>();
+    let deliveries = WebhookDelivery::list_recent(&app_state.db, 100)
+        .await?
+        .into_iter()
+        .map(|delivery| DeliveryInfo {
+            url: delivery.url.unwrap_or_default(),
+            kind: delivery.body.0["kind"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            description: delivery.body.0["description"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            created_at_seconds: delivery.created_at.timestamp(),
+            attempts: delivery.attempts,
+            delivered: delivery.delivered_at.is_some(),
+            pending: delivery.next_attempt_at.is_some(),
+            last_status: delivery.last_status,
+            last_error: delivery.last_error,
+        })
+        .collect::<Vec<_>>();
+
+    let mut context = tera::Context::new();
+    context.insert("csrf_token", &csrf_token.0);
+    context.insert("webhooks", &webhooks);
+    context.insert("deliveries", &deliveries);
+    context.insert("event_kinds", &Webhook::EVENT_KINDS);
+
+    Ok(Html(app_state.engine.render("webhooks", &context)?))
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    url: String,
+    secret: String,
+    /// None checked means every kind
+    #[serde(default)]
+    event_kinds: Vec<String>,
+}
+pub async fn create_webhook(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<CreateWebhookRequest>,
+) -> AppResult<Redirect> {
+    require_admin(&app_state, &user_id).await?;
+
+    let url = request.url.trim();
+    if !Url::parse(url).is_ok_and(|url| url.scheme() == "https" || url.scheme() == "http") {
+        return Err(AppError::Validation(format!(
+            "\"{url}\" isn't an http(s) URL"
+        )));
+    }
+    if request.secret.is_empty() {
+        return Err(AppError::Validation("Webhooks need a secret".to_string()));
+    }
+    if let Some(kind) = request
+        .event_kinds
+        .iter()
+        .find(|kind| !Webhook::EVENT_KINDS.contains(&kind.as_str()))
+    {
+        return Err(AppError::Validation(format!(
+            "\"{kind}\" events can't be sent to webhooks"
+        )));
+    }
+
+    Webhook::insert(&app_state.db, url, &request.secret, &request.event_kinds).await?;
+
+    Ok(Redirect::to("/admin/webhooks"))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteWebhookRequest {
+    webhook_id: String,
+}
+pub async fn delete_webhook(
+    ExtractUserId(user_id): ExtractUserId,
+    State(app_state): State<AppState>,
+    Form(request): Form<DeleteWebhookRequest>,
+) -> AppResult<Redirect> {
+    require_admin(&app_state, &user_id).await?;
+
+    Webhook::delete(&app_state.db, &request.webhook_id).await?;
+
+    Ok(Redirect::to("/admin/webhooks"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(retry_delay(1), chrono::Duration::seconds(30));
+        assert_eq!(retry_delay(2), chrono::Duration::seconds(60));
+        assert_eq!(retry_delay(7), chrono::Duration::seconds(30 * 64));
+    }
+}